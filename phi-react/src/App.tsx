@@ -1,9 +1,10 @@
-import React, { useCallback, useEffect } from 'react';
+import React, { useCallback, useEffect, useState } from 'react';
 import { PlayerCards } from './PlayerCards';
 import { CardPicker } from './CardPicker';
-import { gql, useMutation, useQuery } from '@apollo/client';
+import { gql, useMutation, useQuery, useSubscription } from '@apollo/client';
+import { SubscriptionClient } from 'subscriptions-transport-ws';
 import { GetCards } from './__generated__/GetCards';
-import { GetGameState } from './__generated__/GetGameState';
+import { WatchGameState } from './__generated__/WatchGameState';
 import { GetClientId } from './__generated__/GetClientId';
 import { SetPlayerCard } from './__generated__/SetPlayerCard';
 import { NameSetter } from './NameSetter';
@@ -13,26 +14,26 @@ import { RemovePlayer } from './__generated__/RemovePlayer';
 import { SendHeartbeat } from './__generated__/SendHeartbeat';
 
 const REGISTER = gql`
-  mutation GetClientId {
-    register
+  mutation GetClientId($room: String!) {
+    register(room: $room)
   }
 `;
 
 const REMOVE_PLAYER = gql`
-  mutation RemovePlayer($playerId: UUID!) {
-    removePlayer(playerId: $playerId)
+  mutation RemovePlayer($room: String!, $playerId: UUID!) {
+    removePlayer(room: $room, playerId: $playerId)
   }
 `;
 
 const SEND_HEARTBEAT = gql`
-  mutation SendHeartbeat($playerId: UUID!) {
-    heartbeat(playerId: $playerId)
+  mutation SendHeartbeat($room: String!, $playerId: UUID!) {
+    heartbeat(room: $room, playerId: $playerId)
   }
 `;
 
 const SET_PLAYER_NAME = gql`
-  mutation SetPlayerName($playerId: UUID!, $name: String!) {
-    setPlayerName(playerId: $playerId, name: $name) {
+  mutation SetPlayerName($room: String!, $playerId: UUID!, $name: String!) {
+    setPlayerName(room: $room, playerId: $playerId, name: $name) {
       id
       name
     }
@@ -40,28 +41,36 @@ const SET_PLAYER_NAME = gql`
 `;
 
 const GET_CARDS = gql`
-  query GetCards {
-    cards
+  query GetCards($room: String!) {
+    cards(room: $room) {
+      label
+    }
   }
 `;
 
-const GET_GAME_STATE = gql`
-  query GetGameState {
-    gameState {
+const WATCH_GAME_STATE = gql`
+  subscription WatchGameState($room: String!) {
+    gameState(room: $room) {
       isCalling
       players {
         id
         selectedCard
+        hasVoted
         name
         idle
       }
+      results {
+        consensus {
+          reached
+        }
+      }
     }
   }
 `;
 
 const SET_PLAYER_CARD = gql`
-  mutation SetPlayerCard($playerId: UUID!, $card: Int!) {
-    setPlayerCard(card: $card, playerId: $playerId) {
+  mutation SetPlayerCard($room: String!, $playerId: UUID!, $card: Int!) {
+    setPlayerCard(room: $room, card: $card, playerId: $playerId) {
       id
       selectedCard
     }
@@ -69,30 +78,38 @@ const SET_PLAYER_CARD = gql`
 `;
 
 const CHECK_ADMIN_KEY = gql`
-  mutation CheckAdminKey($key: String!) {
-    adminChallenge(key: $key)
+  mutation CheckAdminKey($room: String!, $key: String!) {
+    adminChallenge(room: $room, key: $key)
   }
 `;
 
 const CALL = gql`
-  mutation Call {
-    call
+  mutation Call($room: String!) {
+    call(room: $room)
   }
 `;
 
 const RESUME = gql`
-  mutation Resume {
-    resume
+  mutation Resume($room: String!) {
+    resume(room: $room)
   }
 `;
 
 const RESET = gql`
-  mutation Reset {
-    reset
+  mutation Reset($room: String!) {
+    reset(room: $room)
   }
 `;
 
-function App() {
+// The room joined when the url doesn't name one.
+const DEFAULT_ROOM = 'default';
+
+type Props = {
+  // Carries the subscriptions, and tells us whether we're connected.
+  socket: SubscriptionClient;
+};
+
+function App({ socket }: Props) {
   // FIXME: look at adding a separate page to ask for a player name.
   //  Should work as a "landing page", and leverage a cookie.
   //  Skip if the player name is already set.
@@ -103,26 +120,33 @@ function App() {
 
   const qs = new URLSearchParams(window.location.search);
   const adminKey = qs.get('key');
+  // Every query and mutation is for this room, the id or the slug.
+  const room = qs.get('room') || DEFAULT_ROOM;
+  const variables = { room };
 
   const [checkAdminKey, { data: adminChallengeData }] =
     useMutation<CheckAdminKey>(CHECK_ADMIN_KEY);
 
   const isAdmin = !!adminChallengeData?.adminChallenge;
-  const { data: cardData } = useQuery<GetCards>(GET_CARDS);
-  const { data: gameStateData } = useQuery<GetGameState>(GET_GAME_STATE, {
-    pollInterval: 750,
-  });
+  const { data: cardData } = useQuery<GetCards>(GET_CARDS, { variables });
 
   const [getClientId, { data: registerData }] =
-    useMutation<GetClientId>(REGISTER);
+    useMutation<GetClientId>(REGISTER, { variables });
+  const clientId = registerData?.register;
+  // Holding this open is also what keeps us from showing as away, so it waits
+  // until we're in the room.
+  const { data: gameStateData } = useSubscription<WatchGameState>(
+    WATCH_GAME_STATE,
+    { variables, skip: !clientId }
+  );
   const [setPlayerCard] = useMutation<SetPlayerCard>(SET_PLAYER_CARD);
   const [setPlayerName] = useMutation<SetPlayerName>(SET_PLAYER_NAME);
   const [removePlayer] = useMutation<RemovePlayer>(REMOVE_PLAYER);
   const [sendHeartbeat] = useMutation<SendHeartbeat>(SEND_HEARTBEAT);
 
-  const [call] = useMutation(CALL);
-  const [resume] = useMutation(RESUME);
-  const [reset] = useMutation(RESET);
+  const [call] = useMutation(CALL, { variables });
+  const [resume] = useMutation(RESUME, { variables });
+  const [reset] = useMutation(RESET, { variables });
 
   useEffect(
     () => {
@@ -130,6 +154,7 @@ function App() {
       if (adminKey) {
         checkAdminKey({
           variables: {
+            room,
             key: adminKey,
           },
         }).catch((reason) => console.error(reason));
@@ -139,7 +164,16 @@ function App() {
     []
   );
 
-  const clientId = registerData?.register;
+  const [connected, setConnected] = useState(false);
+  useEffect(() => {
+    const unsubscribe = [
+      socket.onConnected(() => setConnected(true)),
+      socket.onReconnected(() => setConnected(true)),
+      socket.onDisconnected(() => setConnected(false)),
+    ];
+    return () => unsubscribe.forEach((f) => f());
+  }, [socket]);
+
   useEffect(() => {
     // When a page unloads, try to remove the player from the game.
     //
@@ -147,18 +181,30 @@ function App() {
     // - the request to register can fire, and
     // - the page can unload before the client id comes back
     //
-    // To cover this gap we also use heartbeats to see when the last contact
-    // from a player was, then remove players that haven't phoned home within
-    // some deadline.
-    window.addEventListener('beforeunload', () => {
-      removePlayer({ variables: { playerId: clientId } }).catch((reason) =>
-        console.error(reason)
+    // To cover this gap the server also watches for the game state
+    // subscription closing, then removes players that haven't come back
+    // within some deadline.
+    const onUnload = () => {
+      removePlayer({ variables: { room, playerId: clientId } }).catch(
+        (reason) => console.error(reason)
       );
-    });
+    };
+    window.addEventListener('beforeunload', onUnload);
+    return () => {
+      window.removeEventListener('beforeunload', onUnload);
+    };
+  }, [room, clientId, removePlayer]);
 
+  useEffect(() => {
+    // The open subscription is enough to show we're here. While the socket is
+    // down, fall back on heartbeats so we don't get removed while it
+    // reconnects.
+    if (!clientId || connected) {
+      return;
+    }
     const timer = window.setInterval(() => {
       sendHeartbeat({
-        variables: { playerId: clientId },
+        variables: { room, playerId: clientId },
       }).catch((reason) => {
         console.error(reason);
         // If the heartbeat fails, it could be because the server is down.
@@ -169,9 +215,9 @@ function App() {
     }, 8_000);
 
     return () => {
-      window.clearTimeout(timer);
+      window.clearInterval(timer);
     };
-  }, [clientId, removePlayer, sendHeartbeat]);
+  }, [room, clientId, connected, sendHeartbeat]);
 
   const isCalling = !!gameStateData?.gameState.isCalling;
 
@@ -186,15 +232,15 @@ function App() {
   const players = gameStateData?.gameState.players;
 
   const player = players && clientId && players.find((x) => x.id === clientId);
-  const cards = cardData?.cards;
+  const cards = cardData?.cards.map((card) => card.label);
 
   const onSelectCard = useCallback(
     (card: number) => {
       setPlayerCard({
-        variables: { playerId: clientId, card: card },
+        variables: { room, playerId: clientId, card: card },
       }).catch((reason) => console.error(reason));
     },
-    [setPlayerCard, clientId]
+    [setPlayerCard, room, clientId]
   );
 
   if (!cards || !player || !gameStateData) {
@@ -209,6 +255,7 @@ function App() {
         onSubmit={(name) =>
           setPlayerName({
             variables: {
+              room,
               playerId: clientId,
               name,
             },
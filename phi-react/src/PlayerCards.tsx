@@ -1,5 +1,5 @@
 import React from 'react';
-import { GetGameState } from './__generated__/GetGameState';
+import { WatchGameState } from './__generated__/WatchGameState';
 import Confetti from 'react-dom-confetti';
 
 const confettiConfig = {
@@ -19,27 +19,20 @@ const confettiConfig = {
 type Props = {
   // should be fine to receive as a prop since the websocket broadcast only
   // fires when the gameState data changes.
-  gameStateData: GetGameState;
+  gameStateData: WatchGameState;
   cards: string[];
 };
 
 export function PlayerCards(props: Props) {
-  const { isCalling, players } = props.gameStateData.gameState;
+  const { isCalling, players, results } = props.gameStateData.gameState;
   const { cards } = props;
   const classes = ['player-cards', 'flex', 'space-x-2', 'py-4'];
   if (isCalling) {
     classes.push('calling');
   }
 
-  // Celebrate when everybody picks the same card.
-  const activePlayers = players.filter((p) => !p.idle);
-  const consensus =
-    isCalling &&
-    !!activePlayers.length &&
-    activePlayers[0].selectedCard !== null &&
-    activePlayers.every(
-      (p) => p.selectedCard === activePlayers[0].selectedCard
-    );
+  // Celebrate when the table agrees, by the consensus rule for the room.
+  const consensus = !!results?.consensus.reached;
 
   return (
     <div className={classes.join(' ')}>
@@ -48,9 +41,7 @@ export function PlayerCards(props: Props) {
         return (
           <div key={player.id}>
             <div
-              className={`card ${
-                player.selectedCard === null ? 'undecided' : ''
-              }`}
+              className={`card ${player.hasVoted ? '' : 'undecided'}`}
             >
               <div className={'value'}>
                 {player.selectedCard !== null ? cards[player.selectedCard] : ''}
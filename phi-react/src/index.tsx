@@ -8,47 +8,53 @@ import {
   ApolloClient,
   InMemoryCache,
   HttpLink,
-  // split,
+  split,
 } from '@apollo/client';
-// import { WebSocketLink } from '@apollo/client/link/ws';
-// import { getMainDefinition } from '@apollo/client/utilities';
+import { WebSocketLink } from '@apollo/client/link/ws';
+import { getMainDefinition } from '@apollo/client/utilities';
+import { SubscriptionClient } from 'subscriptions-transport-ws';
+
+const proto = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
+
+// Only connects once the first subscription starts, so the socket carries the
+// session cookie handed out by `register`.
+const socket = new SubscriptionClient(
+  `${proto}//${window.location.host}/gql`,
+  {
+    reconnect: true,
+    lazy: true,
+  }
+);
 
 function getClient() {
   const httpLink = new HttpLink({
     uri: '/gql',
   });
 
-  // const proto = window.location.protocol === 'https:' ? 'wss:' : 'ws:';
-
-  // const wsLink = new WebSocketLink({
-  //   uri: `${proto}//${window.location.host}/gql`,
-  //   options: {
-  //     reconnect: true,
-  //   },
-  // });
-
-  // const splitLink = split(
-  //   ({ query }) => {
-  //     const definition = getMainDefinition(query);
-  //     return (
-  //       definition.kind === 'OperationDefinition' &&
-  //       definition.operation === 'subscription'
-  //     );
-  //   },
-  //   wsLink,
-  //   httpLink
-  // );
+  const wsLink = new WebSocketLink(socket);
+
+  const splitLink = split(
+    ({ query }) => {
+      const definition = getMainDefinition(query);
+      return (
+        definition.kind === 'OperationDefinition' &&
+        definition.operation === 'subscription'
+      );
+    },
+    wsLink,
+    httpLink
+  );
 
   return new ApolloClient({
     cache: new InMemoryCache(),
-    link: httpLink,
+    link: splitLink,
   });
 }
 
 ReactDOM.render(
   <React.StrictMode>
     <ApolloProvider client={getClient()}>
-      <App />
+      <App socket={socket} />
     </ApolloProvider>
   </React.StrictMode>,
   document.getElementById('root')
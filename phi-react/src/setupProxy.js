@@ -5,6 +5,7 @@ module.exports = function (app) {
     '/gql',
     createProxyMiddleware({
       target: 'http://localhost:7878',
+      ws: true,
     })
   );
 };
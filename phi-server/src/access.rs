@@ -0,0 +1,178 @@
+//! Keeping strangers out of password protected rooms.
+//!
+//! Players get in with the room password, or with an invite token minted by
+//! an admin. Tokens look like `<expiry>.<signature>`, where the expiry is in
+//! seconds since the unix epoch and the signature is an HMAC over the room id
+//! and the expiry, keyed with the admin key for the room. Changing the admin
+//! key voids every token handed out with it.
+
+use crate::rooms::RoomId;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, PartialEq)]
+pub enum InviteError {
+    Malformed,
+    BadSignature,
+    Expired,
+}
+
+impl fmt::Display for InviteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InviteError::Malformed => write!(f, "The invite token is not valid."),
+            InviteError::BadSignature => write!(f, "The invite token is not for this room."),
+            InviteError::Expired => write!(f, "The invite token has expired."),
+        }
+    }
+}
+
+impl std::error::Error for InviteError {}
+
+/// Hashes a room password for storage.
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(e) => {
+            log::error!("Stored room password hash is unreadable: {}", e);
+            false
+        }
+    }
+}
+
+fn mac(secret: &str, room: RoomId, expires: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC takes any key");
+    mac.update(room.as_bytes());
+    mac.update(&expires.to_be_bytes());
+    mac
+}
+
+/// Identifies the admin key for `room` without giving it away, so admin
+/// grants kept in the session cookie lapse once the key changes.
+pub fn key_fingerprint(admin_key: &str, room: RoomId) -> String {
+    let mut mac = HmacSha256::new_from_slice(admin_key.as_bytes()).expect("HMAC takes any key");
+    mac.update(b"admin grant");
+    mac.update(room.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Mints a token letting anyone holding it into `room` until `expires_at`.
+pub fn mint_invite(secret: &str, room: RoomId, expires_at: SystemTime) -> String {
+    let expires = expires_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let signature = mac(secret, room, expires).finalize().into_bytes();
+    format!("{}.{}", expires, hex::encode(signature))
+}
+
+/// Checks that `token` was minted for `room` and hasn't expired.
+pub fn check_invite(secret: &str, room: RoomId, token: &str) -> Result<(), InviteError> {
+    let (expires, signature) = token.trim().split_once('.').ok_or(InviteError::Malformed)?;
+    let expires: u64 = expires.parse().map_err(|_| InviteError::Malformed)?;
+    let signature = hex::decode(signature).map_err(|_| InviteError::Malformed)?;
+    mac(secret, room, expires)
+        .verify_slice(&signature)
+        .map_err(|_| InviteError::BadSignature)?;
+    if UNIX_EPOCH + Duration::from_secs(expires) < SystemTime::now() {
+        return Err(InviteError::Expired);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    const SECRET: &str = "admin key";
+
+    fn in_an_hour() -> SystemTime {
+        SystemTime::now() + Duration::from_secs(3600)
+    }
+
+    #[test]
+    fn valid_invite() {
+        let room = Uuid::new_v4();
+        let token = mint_invite(SECRET, room, in_an_hour());
+        assert_eq!(check_invite(SECRET, room, &token), Ok(()));
+    }
+
+    #[test]
+    fn invite_for_another_room() {
+        let token = mint_invite(SECRET, Uuid::new_v4(), in_an_hour());
+        assert_eq!(
+            check_invite(SECRET, Uuid::new_v4(), &token),
+            Err(InviteError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn invite_after_key_change() {
+        let room = Uuid::new_v4();
+        let token = mint_invite(SECRET, room, in_an_hour());
+        assert_eq!(
+            check_invite("new admin key", room, &token),
+            Err(InviteError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn expired_invite() {
+        let room = Uuid::new_v4();
+        let token = mint_invite(SECRET, room, SystemTime::now() - Duration::from_secs(60));
+        assert_eq!(
+            check_invite(SECRET, room, &token),
+            Err(InviteError::Expired)
+        );
+    }
+
+    #[test]
+    fn pushed_back_expiry() {
+        let room = Uuid::new_v4();
+        let token = mint_invite(SECRET, room, SystemTime::now() - Duration::from_secs(60));
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = format!("{}.{}", u64::MAX, signature);
+        assert_eq!(
+            check_invite(SECRET, room, &forged),
+            Err(InviteError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn malformed_invite() {
+        let room = Uuid::new_v4();
+        for token in ["", "nonsense", "soon.abcd", "1700000000.not-hex"] {
+            assert_eq!(
+                check_invite(SECRET, room, token),
+                Err(InviteError::Malformed),
+                "{}",
+                token
+            );
+        }
+    }
+
+    #[test]
+    fn passwords() {
+        let hash = hash_password("hunter2").unwrap();
+        assert!(verify_password(&hash, "hunter2"));
+        assert!(!verify_password(&hash, "hunter3"));
+        assert!(!verify_password("not a hash", "hunter2"));
+    }
+}
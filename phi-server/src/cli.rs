@@ -1,5 +1,7 @@
-use crate::DeckType;
+use actix_web::cookie::SameSite;
+use chrono::{DateTime, Utc};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -7,11 +9,26 @@ pub struct Opt {
     #[structopt(
         long,
         env = "PHI_ADMIN_KEY",
-        help = "The admin key unlocks special features in the UI when \
-        passed as the `key` url parameter. \
+        help = "The admin key for the `default` room unlocks special \
+        features in the UI when passed as the `key` url parameter. \
         Defaults to a random value on startup when not specified."
     )]
     pub admin_key: Option<String>,
+    #[structopt(
+        long,
+        env = "PHI_ADMIN_CREDENTIALS_FILE",
+        parse(from_os_str),
+        help = "A TOML (or JSON, by extension) file listing named admin \
+        credentials as `credential` tables, each with a `name`, a `role` of \
+        `facilitator` or `server-operator`, and a `password_hash`."
+    )]
+    pub admin_credentials_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Read a password from stdin, print its hash for the admin \
+        credentials file and exit."
+    )]
+    pub hash_password: bool,
     #[structopt(
         long,
         env = "PHI_DISCONNECT_TIMEOUT_SECS",
@@ -20,13 +37,198 @@ pub struct Opt {
         dropped from the game."
     )]
     pub disconnect_timeout_secs: u64,
+    #[structopt(
+        long,
+        env = "PHI_MAX_ROOMS",
+        default_value = "100",
+        help = "Creating rooms fails once there are this many. Admins can \
+        delete rooms to make space."
+    )]
+    pub max_rooms: usize,
     #[structopt(
         long,
         env = "PHI_DECK_TYPE",
         default_value = "fib",
-        help = "Set the deck type: `fib` or `days`."
+        help = "Set the deck type by name. `fib` and `days` are built in, \
+        more can be added with `--deck-file`."
     )]
-    pub deck_type: DeckType,
+    pub deck_type: String,
+    #[structopt(
+        long,
+        env = "PHI_DECK_FILE",
+        parse(from_os_str),
+        help = "A TOML (or JSON, by extension) file defining extra decks as a \
+        list of `deck` tables, each with a `name` and a list of `cards`."
+    )]
+    pub deck_file: Option<PathBuf>,
     #[structopt(long, env = "PHI_HTTP_ADDR", default_value = "0.0.0.0:7878")]
     pub http_addr: SocketAddr,
+    #[structopt(
+        long,
+        env = "PHI_STATE_FILE",
+        parse(from_os_str),
+        conflicts_with = "database",
+        help = "When set, rooms and their game state are saved to this file \
+        and restored from it on startup. The audit log is appended to a \
+        `.audit.jsonl` file next to it."
+    )]
+    pub state_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        env = "PHI_STATE_SAVE_DELAY_MS",
+        default_value = "1000",
+        help = "How long to wait after a change before writing the state file. \
+        Changes made in the meantime are saved in the same write."
+    )]
+    pub state_save_delay_ms: u64,
+    #[structopt(
+        long,
+        env = "PHI_DATABASE",
+        parse(from_os_str),
+        help = "When set, rooms and their game state are stored in this SQLite \
+        database. The schema is created or upgraded on startup."
+    )]
+    pub database: Option<PathBuf>,
+    #[structopt(
+        long,
+        env = "PHI_COOKIE_KEY",
+        hide_env_values = true,
+        help = "The secret used to sign session cookies, at least 32 bytes \
+        long. Defaults to a random value on startup, which logs everybody out \
+        on restart."
+    )]
+    pub cookie_key: Option<String>,
+    #[structopt(
+        long,
+        env = "PHI_COOKIE_KEY_FILE",
+        parse(from_os_str),
+        conflicts_with = "cookie-key",
+        help = "Read the cookie key from this file instead."
+    )]
+    pub cookie_key_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        env = "PHI_PREVIOUS_COOKIE_KEY",
+        hide_env_values = true,
+        help = "The cookie key being rotated out. Cookies signed with it are \
+        still accepted, and swapped for ones signed with the current key, \
+        until `--previous-cookie-key-expires`."
+    )]
+    pub previous_cookie_key: Option<String>,
+    #[structopt(
+        long,
+        env = "PHI_PREVIOUS_COOKIE_KEY_FILE",
+        parse(from_os_str),
+        conflicts_with = "previous-cookie-key",
+        help = "Read the previous cookie key from this file instead."
+    )]
+    pub previous_cookie_key_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        env = "PHI_PREVIOUS_COOKIE_KEY_EXPIRES",
+        help = "When the previous cookie key stops being accepted, in RFC 3339 \
+        format, like `2022-03-01T00:00:00Z`. Required along with the previous \
+        key, and unaffected by restarts."
+    )]
+    pub previous_cookie_key_expires: Option<DateTime<Utc>>,
+    #[structopt(
+        long,
+        env = "PHI_COOKIE_SECURE",
+        default_value = "false",
+        parse(try_from_str = parse_bool),
+        help = "Set to `true` to only send the session cookie over HTTPS. Use \
+        this when serving behind TLS."
+    )]
+    pub cookie_secure: bool,
+    #[structopt(
+        long,
+        env = "PHI_COOKIE_SAME_SITE",
+        default_value = "lax",
+        parse(try_from_str = parse_same_site),
+        help = "The `SameSite` setting for the session cookie: `strict`, \
+        `lax` or `none`. Browsers drop `none` cookies unless they're secure, \
+        so `none` needs `--cookie-secure true`."
+    )]
+    pub cookie_same_site: SameSite,
+    #[structopt(
+        long,
+        env = "PHI_OIDC_ISSUER",
+        requires_all = &["oidc-client-id", "oidc-redirect-url"],
+        help = "Lets players sign in with this OpenID Connect provider. Its \
+        settings are discovered from `/.well-known/openid-configuration` \
+        under this URL on startup."
+    )]
+    pub oidc_issuer: Option<String>,
+    #[structopt(long, env = "PHI_OIDC_CLIENT_ID")]
+    pub oidc_client_id: Option<String>,
+    #[structopt(
+        long,
+        env = "PHI_OIDC_CLIENT_SECRET",
+        hide_env_values = true,
+        help = "Only needed for confidential clients, PKCE covers public ones."
+    )]
+    pub oidc_client_secret: Option<String>,
+    #[structopt(
+        long,
+        env = "PHI_OIDC_REDIRECT_URL",
+        help = "Where the provider sends players back to, the public URL of \
+        `/auth/callback` on this server."
+    )]
+    pub oidc_redirect_url: Option<String>,
+    #[structopt(
+        long,
+        env = "PHI_OIDC_SCOPES",
+        default_value = "openid profile email",
+        help = "The scopes asked for when signing in."
+    )]
+    pub oidc_scopes: String,
+    #[structopt(
+        long,
+        env = "PHI_OIDC_GROUPS_CLAIM",
+        default_value = "groups",
+        help = "The ID token claim listing the groups a player is in."
+    )]
+    pub oidc_groups_claim: String,
+    #[structopt(
+        long,
+        env = "PHI_OIDC_ADMIN_GROUP",
+        help = "Players in this group are admins in every room, without \
+        needing the admin key."
+    )]
+    pub oidc_admin_group: Option<String>,
+    #[structopt(
+        long,
+        env = "PHI_OIDC_ADMIN_MAX_AGE_SECS",
+        default_value = "3600",
+        help = "How long membership of the admin group counts for after \
+        signing in. Players taken out of the group at the provider stay \
+        admins until then, and members sign in again to carry on."
+    )]
+    pub oidc_admin_max_age_secs: u64,
+    #[structopt(
+        long,
+        env = "PHI_OIDC_INSECURE",
+        default_value = "false",
+        parse(try_from_str = parse_bool),
+        help = "Set to `true` to allow a provider that isn't behind https, \
+        such as a local mock provider. Never use this in production."
+    )]
+    pub oidc_insecure: bool,
+}
+
+fn parse_bool(s: &str) -> Result<bool, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "1" => Ok(true),
+        "false" | "no" | "0" => Ok(false),
+        _ => Err(format!("Expected `true` or `false`, got `{}`.", s)),
+    }
+}
+
+fn parse_same_site(s: &str) -> Result<SameSite, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "strict" => Ok(SameSite::Strict),
+        "lax" => Ok(SameSite::Lax),
+        "none" => Ok(SameSite::None),
+        _ => Err(format!("Invalid SameSite setting: `{}`.", s)),
+    }
 }
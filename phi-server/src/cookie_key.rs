@@ -0,0 +1,299 @@
+//! Keys for signing the session cookie, and rotating them.
+//!
+//! `CookieSession` only knows about one key, so cookies signed with the
+//! previous key are re-signed with the current one before they reach it, and
+//! the client is handed the re-signed cookie with the response.
+
+use crate::cli::Opt;
+use actix_session::CookieSession;
+use actix_web::body::MessageBody;
+use actix_web::cookie::{Cookie, CookieJar, Key, SameSite};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderValue, COOKIE};
+use rand::RngCore;
+use std::io;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// The name of the session cookie.
+pub const COOKIE_NAME: &str = "phi";
+
+/// The key derivation used for signing needs at least this much to work with.
+const MIN_KEY_LEN: usize = 32;
+
+pub struct CookieKeys {
+    current: Vec<u8>,
+    /// The previous key along with when it stops being accepted.
+    previous: Option<(Key, SystemTime)>,
+    secure: bool,
+    same_site: SameSite,
+}
+
+/// The key passed inline or read from a file, if either was given.
+fn read_key(key: &Option<String>, file: &Option<PathBuf>) -> io::Result<Option<Vec<u8>>> {
+    Ok(match (key, file) {
+        (Some(key), _) => Some(key.as_bytes().to_vec()),
+        (None, Some(path)) => Some(std::fs::read_to_string(path)?.trim().as_bytes().to_vec()),
+        (None, None) => None,
+    })
+}
+
+impl CookieKeys {
+    pub fn from_opts(opts: &Opt) -> io::Result<CookieKeys> {
+        let current = match read_key(&opts.cookie_key, &opts.cookie_key_file)? {
+            Some(key) => key,
+            None => {
+                log::warn!(
+                    "No cookie key given, using a random one. \
+                    Sessions will not survive a restart."
+                );
+                let mut key = vec![0; MIN_KEY_LEN * 2];
+                rand::thread_rng().fill_bytes(&mut key);
+                key
+            }
+        };
+        check_len("cookie key", &current)?;
+
+        let previous = match read_key(&opts.previous_cookie_key, &opts.previous_cookie_key_file)? {
+            Some(key) => {
+                check_len("previous cookie key", &key)?;
+                let expires = opts.previous_cookie_key_expires.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "The previous cookie key needs `--previous-cookie-key-expires`.",
+                    )
+                })?;
+                Some((Key::derive_from(&key), SystemTime::from(expires)))
+            }
+            None => None,
+        };
+        Ok(CookieKeys {
+            current,
+            previous,
+            secure: opts.cookie_secure,
+            same_site: opts.cookie_same_site,
+        })
+    }
+
+    /// The session middleware, signing with the current key.
+    pub fn session(&self) -> CookieSession {
+        CookieSession::signed(&self.current)
+            .name(COOKIE_NAME)
+            .secure(self.secure)
+            .http_only(true)
+            .same_site(self.same_site)
+            .path("/")
+    }
+
+    /// Re-signs a session cookie signed with the previous key, so
+    /// `CookieSession` sees it as valid. Returns the re-signed cookie, to be
+    /// handed to `reissue()` along with the response.
+    ///
+    /// Anything else, including cookies signed with neither key, is left
+    /// alone.
+    pub fn upgrade(&self, req: &mut ServiceRequest) -> Option<Cookie<'static>> {
+        let previous = match &self.previous {
+            Some((key, until)) if SystemTime::now() < *until => key,
+            _ => return None,
+        };
+
+        // Going through `req.cookies()` would cache the parsed cookies, and
+        // `CookieSession` would never see the rewritten header.
+        let cookies: Vec<Cookie<'static>> = req
+            .headers()
+            .get_all(COOKIE)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| Cookie::parse_encoded(pair.trim().to_string()).ok())
+            .collect();
+
+        let mut jar = CookieJar::new();
+        match cookies.iter().find(|cookie| cookie.name() == COOKIE_NAME) {
+            Some(cookie) => jar.add_original(cookie.clone()),
+            None => return None,
+        }
+        let current = Key::derive_from(&self.current);
+        if jar.signed(&current).get(COOKIE_NAME).is_some() {
+            return None;
+        }
+        let value = match jar.signed(previous).get(COOKIE_NAME) {
+            Some(cookie) => cookie.value().to_string(),
+            None => return None,
+        };
+        log::debug!("Re-signing session cookie signed with the previous key");
+        jar.signed_mut(&current)
+            .add(Cookie::new(COOKIE_NAME, value));
+        let resigned = match jar.get(COOKIE_NAME) {
+            Some(cookie) => cookie.clone(),
+            None => return None,
+        };
+
+        let header = cookies
+            .iter()
+            .map(|cookie| {
+                if cookie.name() == COOKIE_NAME {
+                    resigned.encoded().to_string()
+                } else {
+                    cookie.encoded().to_string()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        if let Ok(header) = HeaderValue::from_str(&header) {
+            req.headers_mut().insert(COOKIE, header);
+        }
+        Some(resigned)
+    }
+
+    /// Hands the client the cookie re-signed by `upgrade()`, unless
+    /// `CookieSession` already set a new one. Otherwise clients that only
+    /// ever read would be logged out once the previous key expires.
+    pub fn reissue<B: MessageBody>(&self, res: &mut ServiceResponse<B>, resigned: Cookie<'static>) {
+        if res
+            .response()
+            .cookies()
+            .any(|cookie| cookie.name() == COOKIE_NAME)
+        {
+            return;
+        }
+        let mut cookie = resigned;
+        cookie.set_path("/");
+        cookie.set_http_only(true);
+        cookie.set_secure(self.secure);
+        cookie.set_same_site(self.same_site);
+        if let Err(e) = res.response_mut().add_cookie(&cookie) {
+            log::error!("Failed to re-issue the session cookie: {}", e);
+        }
+    }
+}
+
+fn check_len(what: &str, key: &[u8]) -> io::Result<()> {
+    if key.len() < MIN_KEY_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "The {} needs to be at least {} bytes long.",
+                what, MIN_KEY_LEN
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+    use actix_web::HttpResponse;
+    use std::time::Duration;
+
+    const CURRENT: &[u8] = b"the current cookie key, at least 32 bytes";
+    const PREVIOUS: &[u8] = b"the previous cookie key, at least 32 bytes";
+    const OTHER: &[u8] = b"some other cookie key, at least 32 bytes";
+
+    fn keys(until: SystemTime) -> CookieKeys {
+        CookieKeys {
+            current: CURRENT.to_vec(),
+            previous: Some((Key::derive_from(PREVIOUS), until)),
+            secure: true,
+            same_site: SameSite::Strict,
+        }
+    }
+
+    fn in_a_minute() -> SystemTime {
+        SystemTime::now() + Duration::from_secs(60)
+    }
+
+    /// A `Cookie` header holding a session cookie signed with `key`, next to
+    /// one that isn't ours.
+    fn header(key: &[u8]) -> String {
+        let mut jar = CookieJar::new();
+        jar.signed_mut(&Key::derive_from(key))
+            .add(Cookie::new(COOKIE_NAME, "session"));
+        let session = jar.get(COOKIE_NAME).unwrap().encoded().to_string();
+        format!("theme=dark; {}", session)
+    }
+
+    /// The `Cookie` header once `keys` are done with it.
+    fn upgraded(keys: &CookieKeys, header: &str) -> String {
+        let mut req = TestRequest::default()
+            .insert_header((COOKIE, header))
+            .to_srv_request();
+        let _ = keys.upgrade(&mut req);
+        req.headers()
+            .get(COOKIE)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    /// The session cookie value, if it is signed with the current key.
+    fn session_value(header: &str) -> Option<String> {
+        let mut jar = CookieJar::new();
+        for pair in header.split(';') {
+            jar.add_original(Cookie::parse_encoded(pair.trim().to_string()).unwrap());
+        }
+        jar.signed(&Key::derive_from(CURRENT))
+            .get(COOKIE_NAME)
+            .map(|cookie| cookie.value().to_string())
+    }
+
+    #[test]
+    fn previous_key_cookies_are_resigned_during_the_grace_period() {
+        let keys = keys(in_a_minute());
+        let header = upgraded(&keys, &header(PREVIOUS));
+        assert_eq!(session_value(&header).as_deref(), Some("session"));
+        assert!(header.starts_with("theme=dark; "));
+    }
+
+    #[test]
+    fn previous_key_cookies_are_refused_after_the_grace_period() {
+        let keys = keys(SystemTime::now());
+        let original = header(PREVIOUS);
+        let header = upgraded(&keys, &original);
+        assert_eq!(header, original);
+        assert_eq!(session_value(&header), None);
+    }
+
+    #[test]
+    fn other_cookies_are_left_alone() {
+        let keys = keys(in_a_minute());
+        for original in [header(CURRENT), header(OTHER), "theme=dark".to_string()] {
+            assert_eq!(upgraded(&keys, &original), original);
+        }
+    }
+
+    #[test]
+    fn resigned_cookies_are_handed_back_to_the_client() {
+        let keys = keys(in_a_minute());
+        let mut req = TestRequest::default()
+            .insert_header((COOKIE, header(PREVIOUS)))
+            .to_srv_request();
+        let resigned = keys.upgrade(&mut req).unwrap();
+
+        let mut res = req.into_response(HttpResponse::Ok().finish());
+        keys.reissue(&mut res, resigned.clone());
+        let cookie = res.response().cookies().next().unwrap();
+        assert_eq!(
+            session_value(&cookie.encoded().stripped().to_string()).as_deref(),
+            Some("session")
+        );
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Strict));
+
+        // A session changed by the request has its own cookie already.
+        let mut res = TestRequest::default().to_srv_response(
+            HttpResponse::Ok()
+                .cookie(Cookie::new(COOKIE_NAME, "changed"))
+                .finish(),
+        );
+        keys.reissue(&mut res, resigned);
+        let values: Vec<_> = res
+            .response()
+            .cookies()
+            .map(|cookie| cookie.value().to_string())
+            .collect();
+        assert_eq!(values, ["changed"]);
+    }
+}
@@ -0,0 +1,232 @@
+//! Named admin credentials, so facilitators don't all share the admin key.
+//!
+//! Credentials are loaded from the file passed via `--admin-credentials-file`
+//! and only hold password hashes, made with `--hash-password`. For example:
+//!
+//! ```toml
+//! [[credential]]
+//! name = "alice"
+//! role = "server-operator"
+//! password_hash = "$argon2id$v=19$m=4096,t=3,p=1$..."
+//!
+//! [[credential]]
+//! name = "bob"
+//! role = "facilitator"
+//! rooms = ["team-a", "team-b"]
+//! password_hash = "$argon2id$v=19$m=4096,t=3,p=1$..."
+//! ```
+
+use crate::rooms::Room;
+use argon2::password_hash::PasswordHash;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::OnceLock;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a signed in admin may do.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AdminRole {
+    /// Runs games in the rooms listed for the credential.
+    Facilitator,
+    /// Runs games in any room, and can read the audit log.
+    ServerOperator,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Credential {
+    pub name: String,
+    pub role: AdminRole,
+    /// The rooms a facilitator runs, by id or slug.
+    #[serde(default)]
+    rooms: Vec<String>,
+    password_hash: String,
+}
+
+impl Credential {
+    /// Identifies the password hash without giving it away, so sessions
+    /// signed in with the credential lapse once the password changes.
+    pub fn fingerprint(&self) -> String {
+        let mut mac =
+            HmacSha256::new_from_slice(self.password_hash.as_bytes()).expect("HMAC takes any key");
+        mac.update(b"admin credential");
+        mac.update(self.name.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Whether the credential grants admin privileges in `room`.
+    pub fn runs(&self, room: &Room) -> bool {
+        self.role == AdminRole::ServerOperator
+            || self
+                .rooms
+                .iter()
+                .any(|key| *key == room.slug || *key == room.id.to_string())
+    }
+}
+
+#[derive(Debug)]
+pub enum CredentialsError {
+    Read(std::io::Error),
+    Parse(String),
+    BlankName,
+    DuplicateName(String),
+    BadHash(String),
+}
+
+impl fmt::Display for CredentialsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CredentialsError::Read(e) => write!(f, "Failed to read admin credentials file: {}", e),
+            CredentialsError::Parse(e) => {
+                write!(f, "Failed to parse admin credentials file: {}", e)
+            }
+            CredentialsError::BlankName => write!(f, "An admin credential has no name."),
+            CredentialsError::DuplicateName(name) => {
+                write!(f, "More than one admin credential is named `{}`.", name)
+            }
+            CredentialsError::BadHash(name) => write!(
+                f,
+                "The password hash for admin credential `{}` is unreadable.",
+                name
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CredentialsError {}
+
+#[derive(Debug, Default, Deserialize)]
+struct CredentialsFile {
+    #[serde(default)]
+    credential: Vec<Credential>,
+}
+
+#[derive(Debug, Default)]
+pub struct AdminCredentials {
+    credentials: HashMap<String, Credential>,
+}
+
+impl AdminCredentials {
+    /// The credentials found in `path`, or none at all.
+    pub fn load(path: Option<&Path>) -> Result<AdminCredentials, CredentialsError> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(AdminCredentials::default()),
+        };
+
+        let text = std::fs::read_to_string(path).map_err(CredentialsError::Read)?;
+        let is_json = path
+            .extension()
+            .map(|ext| ext.eq_ignore_ascii_case("json"))
+            .unwrap_or(false);
+        AdminCredentials::parse(&text, is_json)
+    }
+
+    /// The credentials in `text`, in JSON or TOML.
+    pub fn parse(text: &str, is_json: bool) -> Result<AdminCredentials, CredentialsError> {
+        let mut credentials = AdminCredentials::default();
+        let file: CredentialsFile = if is_json {
+            serde_json::from_str(text).map_err(|e| CredentialsError::Parse(e.to_string()))?
+        } else {
+            toml::from_str(text).map_err(|e| CredentialsError::Parse(e.to_string()))?
+        };
+
+        for credential in file.credential {
+            if credential.name.trim().is_empty() {
+                return Err(CredentialsError::BlankName);
+            }
+            if PasswordHash::new(&credential.password_hash).is_err() {
+                return Err(CredentialsError::BadHash(credential.name));
+            }
+            if credentials.credentials.contains_key(&credential.name) {
+                return Err(CredentialsError::DuplicateName(credential.name));
+            }
+            if credential.role == AdminRole::Facilitator && credential.rooms.is_empty() {
+                log::warn!(
+                    "Admin credential `{}` is for a facilitator, but lists no rooms to run.",
+                    credential.name
+                );
+            }
+            log::info!(
+                "Loaded admin credential `{}` ({:?})",
+                credential.name,
+                credential.role
+            );
+            credentials
+                .credentials
+                .insert(credential.name.clone(), credential);
+        }
+        Ok(credentials)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.credentials.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Credential> {
+        self.credentials.get(name)
+    }
+
+    /// The credential, if the password is right for it.
+    ///
+    /// Unknown names are checked against a stand-in hash, so they take as
+    /// long to turn down as wrong passwords do.
+    pub fn verify(&self, name: &str, password: &str) -> Option<&Credential> {
+        match self.get(name) {
+            Some(credential) => Some(credential)
+                .filter(|_| crate::access::verify_password(&credential.password_hash, password)),
+            None => {
+                crate::access::verify_password(stand_in_hash(), password);
+                None
+            }
+        }
+    }
+}
+
+/// A hash no password is checked against for real, made with the same
+/// parameters as `--hash-password` uses.
+fn stand_in_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| crate::access::hash_password("stand-in").expect("hashing a fixed password"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(password: &str) -> AdminCredentials {
+        let hash = crate::access::hash_password(password).unwrap();
+        AdminCredentials::parse(
+            &format!(
+                r#"
+                [[credential]]
+                name = "alice"
+                role = "server-operator"
+                password_hash = "{hash}"
+                "#
+            ),
+            false,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn verify_checks_the_name_and_password() {
+        let credentials = credentials("hunter2");
+        assert!(credentials.verify("alice", "hunter2").is_some());
+        assert!(credentials.verify("alice", "hunter3").is_none());
+        assert!(credentials.verify("bob", "hunter2").is_none());
+    }
+
+    #[test]
+    fn fingerprints_change_with_the_password() {
+        let before = credentials("hunter2").get("alice").unwrap().fingerprint();
+        let after = credentials("hunter3").get("alice").unwrap().fingerprint();
+        assert_ne!(before, after);
+    }
+}
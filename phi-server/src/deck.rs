@@ -0,0 +1,391 @@
+//! The decks of cards players choose from.
+//!
+//! A couple of decks are built in, and more can be defined in a file passed
+//! via `--deck-file`, for example:
+//!
+//! ```toml
+//! [[deck]]
+//! name = "hours"
+//! cards = ["1", "2", "4", "8", "?", "☕"]
+//!
+//! [[deck]]
+//! name = "tshirt"
+//! cards = [
+//!     { label = "S", value = 1 },
+//!     { label = "M", value = 3 },
+//!     { label = "L", value = 8 },
+//!     { label = "XL", value = 20 },
+//!     "?",
+//! ]
+//! ```
+//!
+//! Cards given as a bare label are numeric when the label is a number, and
+//! otherwise need to be one of the special cards ("?", "∞" or "☕").
+//!
+//! The same structure is accepted as JSON when the file name ends in `.json`.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+/// The names of the cards in the planning poker deck.
+pub const FIB_DECK: [&str; 12] = [
+    "0", "1", "2", "3", "5", "8", "13", "21", "100", "∞", "?", "☕",
+];
+pub const DAYS_DECK: [&str; 9] = ["0.5", "1", "1.5", "2", "3", "5", "∞", "?", "☕"];
+
+/// More than this and the cards won't fit on screen.
+const MAX_CARDS: usize = 24;
+const MAX_LABEL_LEN: usize = 16;
+
+#[derive(Debug)]
+pub enum DeckError {
+    Read(std::io::Error),
+    Parse(String),
+    Empty(String),
+    TooManyCards(String, usize),
+    BlankLabel(String),
+    LabelTooLong(String, String),
+    DuplicateLabel(String, String),
+    DuplicateDeck(String),
+    UnknownDeck(String),
+}
+
+impl fmt::Display for DeckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeckError::Read(e) => write!(f, "Failed to read deck file: {}", e),
+            DeckError::Parse(e) => write!(f, "Failed to parse deck file: {}", e),
+            DeckError::Empty(deck) => write!(f, "Deck `{}` has no cards.", deck),
+            DeckError::TooManyCards(deck, count) => write!(
+                f,
+                "Deck `{}` has {} cards. The limit is {}.",
+                deck, count, MAX_CARDS
+            ),
+            DeckError::BlankLabel(deck) => write!(f, "Deck `{}` has a card with no label.", deck),
+            DeckError::LabelTooLong(deck, label) => write!(
+                f,
+                "Deck `{}` has a card label longer than {} characters: `{}`.",
+                deck, MAX_LABEL_LEN, label
+            ),
+            DeckError::DuplicateLabel(deck, label) => {
+                write!(f, "Deck `{}` has more than one `{}` card.", deck, label)
+            }
+            DeckError::DuplicateDeck(deck) => {
+                write!(f, "More than one deck is named `{}`.", deck)
+            }
+            DeckError::UnknownDeck(deck) => write!(f, "Invalid deck type: `{}`.", deck),
+        }
+    }
+}
+
+impl std::error::Error for DeckError {}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CardKind {
+    /// An actual estimate.
+    Numeric,
+    /// "I don't know."
+    Unknown,
+    /// "This is too big to estimate."
+    Infinite,
+    /// "I need a break."
+    Break,
+}
+
+impl CardKind {
+    /// Works out what the special cards are from their label.
+    fn from_label(label: &str) -> Option<CardKind> {
+        match label {
+            "?" => Some(CardKind::Unknown),
+            "∞" | "inf" => Some(CardKind::Infinite),
+            "☕" | "coffee" => Some(CardKind::Break),
+            _ => None,
+        }
+    }
+}
+
+/// How a card is written in a deck file.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum CardDef {
+    Label(String),
+    Full {
+        label: String,
+        value: Option<f64>,
+        kind: Option<CardKind>,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(try_from = "CardDef")]
+pub struct Card {
+    /// What's shown on the card.
+    pub label: String,
+    /// What the card is worth, for numeric cards.
+    pub value: Option<f64>,
+    pub kind: CardKind,
+}
+
+impl TryFrom<CardDef> for Card {
+    type Error = String;
+
+    fn try_from(def: CardDef) -> Result<Self, Self::Error> {
+        let (label, value, kind) = match def {
+            CardDef::Label(label) => (label, None, None),
+            CardDef::Full { label, value, kind } => (label, value, kind),
+        };
+        let value = value.or_else(|| label.trim().parse::<f64>().ok());
+        let kind = kind
+            .or_else(|| CardKind::from_label(label.trim()))
+            .unwrap_or(CardKind::Numeric);
+        match (kind, value) {
+            (CardKind::Numeric, None) => Err(format!(
+                "Card `{}` is not a number, so it needs a `value` or a `kind`.",
+                label
+            )),
+            (CardKind::Numeric, Some(value)) if !value.is_finite() => {
+                Err(format!("Card `{}` has a value that is not finite.", label))
+            }
+            (CardKind::Numeric, value) => Ok(Card { label, value, kind }),
+            // Only numeric cards get to carry a value.
+            (kind, _) => Ok(Card {
+                label,
+                value: None,
+                kind,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Deck {
+    pub name: String,
+    pub cards: Vec<Card>,
+}
+
+impl Deck {
+    fn builtin(name: &str, labels: &[&str]) -> Deck {
+        Deck {
+            name: name.to_string(),
+            cards: labels
+                .iter()
+                .map(|label| {
+                    Card::try_from(CardDef::Label(label.to_string())).expect("valid built in card")
+                })
+                .collect(),
+        }
+    }
+
+    /// The labels shown on each card.
+    pub fn labels(&self) -> Vec<&str> {
+        self.cards.iter().map(|card| card.label.as_str()).collect()
+    }
+
+    fn validate(&self) -> Result<(), DeckError> {
+        if self.cards.is_empty() {
+            return Err(DeckError::Empty(self.name.clone()));
+        }
+        if self.cards.len() > MAX_CARDS {
+            return Err(DeckError::TooManyCards(self.name.clone(), self.cards.len()));
+        }
+        let mut seen = HashSet::new();
+        for label in self.cards.iter().map(|card| &card.label) {
+            if label.trim().is_empty() {
+                return Err(DeckError::BlankLabel(self.name.clone()));
+            }
+            if label.chars().count() > MAX_LABEL_LEN {
+                return Err(DeckError::LabelTooLong(self.name.clone(), label.clone()));
+            }
+            if !seen.insert(label) {
+                return Err(DeckError::DuplicateLabel(self.name.clone(), label.clone()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DeckFile {
+    deck: Vec<Deck>,
+}
+
+/// Every deck available to the server, built in or otherwise.
+#[derive(Debug)]
+pub struct Decks {
+    decks: Vec<Arc<Deck>>,
+}
+
+impl Default for Decks {
+    fn default() -> Self {
+        Decks {
+            decks: vec![
+                Arc::new(Deck::builtin("fib", &FIB_DECK)),
+                Arc::new(Deck::builtin("days", &DAYS_DECK)),
+            ],
+        }
+    }
+}
+
+impl Decks {
+    /// The built in decks, plus any found in `path`.
+    ///
+    /// Decks in the file replace built in decks with the same name.
+    pub fn load(path: Option<&Path>) -> Result<Decks, DeckError> {
+        let mut decks = Decks::default();
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(decks),
+        };
+
+        let text = std::fs::read_to_string(path).map_err(DeckError::Read)?;
+        let is_json = path
+            .extension()
+            .map(|ext| ext.eq_ignore_ascii_case("json"))
+            .unwrap_or(false);
+        let file: DeckFile = if is_json {
+            serde_json::from_str(&text).map_err(|e| DeckError::Parse(e.to_string()))?
+        } else {
+            toml::from_str(&text).map_err(|e| DeckError::Parse(e.to_string()))?
+        };
+
+        let mut names = HashSet::new();
+        for deck in file.deck {
+            deck.validate()?;
+            if !names.insert(deck.name.clone()) {
+                return Err(DeckError::DuplicateDeck(deck.name));
+            }
+            log::info!(
+                "Loaded deck `{}` with {} cards",
+                deck.name,
+                deck.cards.len()
+            );
+            decks.decks.retain(|other| other.name != deck.name);
+            decks.decks.push(Arc::new(deck));
+        }
+        Ok(decks)
+    }
+
+    pub fn list(&self) -> &[Arc<Deck>] {
+        &self.decks
+    }
+
+    pub fn get(&self, name: &str) -> Result<Arc<Deck>, DeckError> {
+        self.decks
+            .iter()
+            .find(|deck| deck.name == name)
+            .cloned()
+            .ok_or_else(|| DeckError::UnknownDeck(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Result<Vec<Deck>, DeckError> {
+        let file: DeckFile = toml::from_str(text).map_err(|e| DeckError::Parse(e.to_string()))?;
+        for deck in &file.deck {
+            deck.validate()?;
+        }
+        Ok(file.deck)
+    }
+
+    fn deck_with(cards: &[&str]) -> String {
+        let cards: Vec<_> = cards.iter().map(|card| format!("{:?}", card)).collect();
+        format!(
+            "[[deck]]\nname = \"test\"\ncards = [{}]\n",
+            cards.join(", ")
+        )
+    }
+
+    #[test]
+    fn builtin_decks_are_valid() {
+        for deck in Decks::default().list() {
+            deck.validate().unwrap();
+        }
+    }
+
+    #[test]
+    fn card_kinds() {
+        let decks = parse(
+            r#"
+            [[deck]]
+            name = "tshirt"
+            cards = [{ label = "S", value = 1 }, "2.5", "?", "∞", "coffee"]
+            "#,
+        )
+        .unwrap();
+        let cards = &decks[0].cards;
+        assert_eq!(cards[0].value, Some(1.0));
+        assert_eq!(cards[1].value, Some(2.5));
+        let kinds: Vec<_> = cards.iter().map(|card| card.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                CardKind::Numeric,
+                CardKind::Numeric,
+                CardKind::Unknown,
+                CardKind::Infinite,
+                CardKind::Break
+            ]
+        );
+    }
+
+    #[test]
+    fn label_needs_a_value() {
+        assert!(matches!(
+            parse(&deck_with(&["1", "XL"])),
+            Err(DeckError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn empty() {
+        assert!(matches!(parse(&deck_with(&[])), Err(DeckError::Empty(_))));
+    }
+
+    #[test]
+    fn too_many_cards() {
+        let labels: Vec<_> = (0..=MAX_CARDS).map(|n| n.to_string()).collect();
+        let labels: Vec<_> = labels.iter().map(String::as_str).collect();
+        assert!(parse(&deck_with(&labels[..MAX_CARDS])).is_ok());
+        assert!(matches!(
+            parse(&deck_with(&labels)),
+            Err(DeckError::TooManyCards(_, 25))
+        ));
+    }
+
+    #[test]
+    fn blank_label() {
+        let text = deck_with(&["1"]).replace("\"1\"", "{ label = \" \", value = 1 }");
+        assert!(matches!(parse(&text), Err(DeckError::BlankLabel(_))));
+    }
+
+    #[test]
+    fn label_too_long() {
+        let text =
+            deck_with(&["1"]).replace("\"1\"", "{ label = \"extra extra large\", value = 1 }");
+        assert!(matches!(parse(&text), Err(DeckError::LabelTooLong(..))));
+    }
+
+    #[test]
+    fn duplicate_label() {
+        assert!(matches!(
+            parse(&deck_with(&["1", "2", "1"])),
+            Err(DeckError::DuplicateLabel(_, label)) if label == "1"
+        ));
+    }
+
+    #[test]
+    fn unknown_deck() {
+        assert!(matches!(
+            Decks::default().get("tarot"),
+            Err(DeckError::UnknownDeck(_))
+        ));
+    }
+}
@@ -0,0 +1,270 @@
+//! Keeps the admin-only mutations for those who know the admin key, are
+//! signed in with an admin credential, or are in the admin group at the
+//! identity provider, and what goes on in password protected rooms for the
+//! players let in.
+//!
+//! Whatever admins do gets recorded in the audit log, by each resolver once
+//! it has succeeded.
+
+use crate::access::key_fingerprint;
+use crate::credentials::{AdminRole, Credential};
+use crate::gql::SessionIdentity;
+use crate::rooms::{Room, RoomId, RoomRegistry};
+use crate::storage::AuditEntry;
+use async_graphql::{Context, Error, ErrorExtensions, Guard, Result};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Rooms the client has passed the admin challenge for, each with the
+/// fingerprint of the admin key it was passed with.
+///
+/// Loaded from the actix session before each request and written back after,
+/// since the session itself can't be handed to the resolvers.
+#[derive(Debug, Default)]
+pub struct AdminGrants {
+    rooms: Mutex<HashMap<RoomId, String>>,
+    /// Set for members of the identity provider's admin group.
+    everywhere: bool,
+    /// The admin credential signed in with, good for the rooms it runs.
+    credential: Mutex<Option<Credential>>,
+    changed: AtomicBool,
+    credential_changed: AtomicBool,
+}
+
+impl AdminGrants {
+    /// Grants from before the admin key for a room changed, or for rooms
+    /// that are gone, are dropped.
+    pub fn new(
+        rooms: HashMap<RoomId, String>,
+        registry: &RoomRegistry,
+        everywhere: bool,
+        credential: Option<Credential>,
+    ) -> Self {
+        let granted = rooms.len();
+        let rooms: HashMap<_, _> = rooms
+            .into_iter()
+            .filter(|(room, fingerprint)| {
+                registry.get(&room.to_string()).is_some_and(|room| {
+                    *fingerprint == key_fingerprint(&room.session.admin_key, room.id)
+                })
+            })
+            .collect();
+        AdminGrants {
+            changed: AtomicBool::new(rooms.len() != granted),
+            rooms: Mutex::new(rooms),
+            everywhere,
+            credential: Mutex::new(credential),
+            credential_changed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn contains(&self, room: &Room) -> bool {
+        self.everywhere
+            || self
+                .credential
+                .lock()
+                .unwrap()
+                .as_ref()
+                .is_some_and(|credential| credential.runs(room))
+            || self.rooms.lock().unwrap().contains_key(&room.id)
+    }
+
+    pub fn credential(&self) -> Option<Credential> {
+        self.credential.lock().unwrap().clone()
+    }
+
+    /// Swaps the signed in credential, `None` signing out.
+    pub fn set_credential(&self, credential: Option<Credential>) {
+        *self.credential.lock().unwrap() = credential;
+        self.credential_changed.store(true, Ordering::Relaxed);
+    }
+
+    /// The signed in credential, if it changed during the request.
+    pub fn take_credential_change(&self) -> Option<Option<Credential>> {
+        if !self.credential_changed.swap(false, Ordering::Relaxed) {
+            return None;
+        }
+        Some(self.credential())
+    }
+
+    /// Who to put down in the audit log.
+    pub fn actor(&self) -> String {
+        match self.credential() {
+            Some(credential) => credential.name,
+            None if self.everywhere => String::from("(admin group)"),
+            None => String::from("(admin key)"),
+        }
+    }
+
+    /// Grants admin privileges in `room` for as long as its key is
+    /// `admin_key`.
+    pub fn grant(&self, room: RoomId, admin_key: &str) {
+        let fingerprint = key_fingerprint(admin_key, room);
+        if self.rooms.lock().unwrap().insert(room, fingerprint.clone()) != Some(fingerprint) {
+            self.changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    pub fn revoke(&self, room: RoomId) {
+        if self.rooms.lock().unwrap().remove(&room).is_some() {
+            self.changed.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// The granted rooms, if they changed during the request.
+    pub fn take_changes(&self) -> Option<HashMap<RoomId, String>> {
+        if !self.changed.swap(false, Ordering::Relaxed) {
+            return None;
+        }
+        Some(self.rooms.lock().unwrap().clone())
+    }
+}
+
+/// Whether the client holds an admin grant for the room.
+pub fn is_admin(ctx: &Context<'_>, room: RoomId) -> bool {
+    let registry = ctx.data_unchecked::<Arc<RoomRegistry>>();
+    ctx.data_opt::<Arc<AdminGrants>>()
+        .zip(registry.get(&room.to_string()))
+        .map(|(grants, room)| grants.contains(&room))
+        .unwrap_or(false)
+}
+
+/// Records the field being resolved in the audit log, as done by the client.
+///
+/// `details` is for the arguments worth keeping and the outcome, and must
+/// never hold a password or key.
+pub fn audit(
+    ctx: &Context<'_>,
+    room: Option<RoomId>,
+    target: Option<String>,
+    details: impl Into<String>,
+) {
+    let details = details.into();
+    let registry = ctx.data_unchecked::<Arc<RoomRegistry>>();
+    let SessionIdentity { id, name } = ctx.data_unchecked::<SessionIdentity>().clone();
+    let actor = ctx
+        .data_opt::<Arc<AdminGrants>>()
+        .map(|grants| grants.actor())
+        .unwrap_or_default();
+    registry.audit(AuditEntry {
+        at: SystemTime::now(),
+        actor,
+        player_id: id,
+        player_name: name,
+        room_id: room,
+        action: ctx.field().name().to_string(),
+        target,
+        details: Some(details).filter(|details| !details.is_empty()),
+    });
+}
+
+/// An error clients can tell apart from the others by its `code` extension.
+pub fn coded(code: &'static str, message: impl Into<String>) -> Error {
+    Error::new(message).extend_with(|_, e| e.set("code", code))
+}
+
+pub fn forbidden(message: impl Into<String>) -> Error {
+    coded("FORBIDDEN", message)
+}
+
+/// Fails the field unless the client holds an admin grant for `room`.
+///
+/// Nothing is audited here, since the guard can't tell whether the field
+/// will go on to succeed.
+pub struct AdminGuard {
+    room: String,
+}
+
+impl AdminGuard {
+    pub fn new(room: &str) -> Self {
+        AdminGuard {
+            room: room.to_string(),
+        }
+    }
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Guard for AdminGuard {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        let registry = ctx.data_unchecked::<Arc<RoomRegistry>>();
+        // Unknown rooms are left for the resolver to report.
+        let room = match registry.get(&self.room) {
+            Some(room) => room,
+            None => return Ok(()),
+        };
+        if is_admin(ctx, room.id) {
+            Ok(())
+        } else {
+            Err(forbidden(format!(
+                "Admin privileges are required for room `{}`.",
+                room.slug
+            )))
+        }
+    }
+}
+
+/// Fails the field unless the client may look into `room`. Anyone may for
+/// open rooms, but password protected ones are kept to the players already
+/// in them and to admins.
+pub struct MemberGuard {
+    room: String,
+}
+
+impl MemberGuard {
+    pub fn new(room: &str) -> Self {
+        MemberGuard {
+            room: room.to_string(),
+        }
+    }
+}
+
+#[async_graphql::async_trait::async_trait]
+impl Guard for MemberGuard {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        let registry = ctx.data_unchecked::<Arc<RoomRegistry>>();
+        // Unknown rooms are left for the resolver to report.
+        let room = match registry.get(&self.room) {
+            Some(room) => room,
+            None => return Ok(()),
+        };
+        if is_admin(ctx, room.id) {
+            return Ok(());
+        }
+        let identity = ctx.data_opt::<SessionIdentity>();
+        let allowed = room.session.read(|game_state| {
+            game_state.settings.password_hash.is_none()
+                || identity.is_some_and(|identity| game_state.players.contains_key(&identity.id))
+        })?;
+        if allowed {
+            Ok(())
+        } else {
+            Err(coded(
+                "PASSWORD_REQUIRED",
+                format!(
+                    "Room `{}` needs a password or an invite to join.",
+                    room.slug
+                ),
+            ))
+        }
+    }
+}
+
+/// Fails the field unless the client is signed in as a server operator.
+pub struct OperatorGuard;
+
+#[async_graphql::async_trait::async_trait]
+impl Guard for OperatorGuard {
+    async fn check(&self, ctx: &Context<'_>) -> Result<()> {
+        let role = ctx
+            .data_opt::<Arc<AdminGrants>>()
+            .and_then(|grants| grants.credential())
+            .map(|credential| credential.role);
+        if role == Some(AdminRole::ServerOperator) {
+            Ok(())
+        } else {
+            Err(forbidden("Only server operators can do that."))
+        }
+    }
+}
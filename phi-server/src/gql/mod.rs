@@ -1,19 +1,48 @@
+use crate::credentials::AdminCredentials;
+use crate::gql::guard::AdminGrants;
+use crate::oidc::Provider;
 use crate::poker::PlayerId;
+use crate::rooms::{RoomId, RoomRegistry};
 use actix_session::Session;
-use actix_web::{guard, web, HttpRequest, HttpResponse, Result};
+use actix_web::{guard as web_guard, web, HttpRequest, HttpResponse, Result};
 use async_graphql::http::{playground_source, GraphQLPlaygroundConfig};
-use async_graphql::Schema;
+use async_graphql::{Data, Schema};
 use async_graphql_actix_web::{GraphQLRequest, GraphQLResponse, GraphQLSubscription};
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 
+pub mod guard;
 pub mod model;
 
+/// What players are called until they pick a name.
+pub const DEFAULT_PLAYER_NAME: &str = "Guest";
+
+/// Session keys for the admin keys matched, by room, and the name and
+/// fingerprint of the admin credential signed in with.
+const ADMIN_ROOMS_KEY: &str = "admin_rooms";
+const ADMIN_CREDENTIAL_KEY: &str = "admin_credential";
+
 #[derive(Clone, Debug)]
 pub struct SessionIdentity {
     name: String,
     id: PlayerId,
 }
 
+/// A name the player gave themselves during the request, written back to the
+/// session after it so the next room they join uses it too.
+#[derive(Debug, Default)]
+pub struct NameChange(Mutex<Option<String>>);
+
+impl NameChange {
+    pub fn set(&self, name: String) {
+        *self.0.lock().unwrap() = Some(name);
+    }
+
+    pub fn take(&self) -> Option<String> {
+        self.0.lock().unwrap().take()
+    }
+}
+
 pub fn get_session_identity(session: &Session) -> SessionIdentity {
     let id: PlayerId = {
         let sess_player_id = session.get::<PlayerId>("player_id").unwrap();
@@ -34,7 +63,7 @@ pub fn get_session_identity(session: &Session) -> SessionIdentity {
         let sess_player_name = session.get::<String>("player_name").unwrap();
         match sess_player_name {
             None => {
-                let name = String::from("Guest");
+                let name = String::from(DEFAULT_PLAYER_NAME);
                 log::debug!("player name not present in request, setting name={name}");
                 session.insert("player_name", name.clone()).unwrap();
                 name
@@ -49,31 +78,99 @@ pub fn get_session_identity(session: &Session) -> SessionIdentity {
     SessionIdentity { id, name }
 }
 
+pub fn get_admin_grants(
+    session: &Session,
+    registry: &RoomRegistry,
+    credentials: &AdminCredentials,
+    oidc: Option<&Provider>,
+) -> AdminGrants {
+    let rooms = session
+        .get::<HashMap<RoomId, String>>(ADMIN_ROOMS_KEY)
+        .unwrap_or_else(|e| {
+            log::warn!("Ignoring unreadable admin grants: {e}");
+            None
+        })
+        .unwrap_or_default();
+    let everywhere = oidc.is_some_and(|provider| provider.grants_admin(session));
+    // Credentials taken out of the file or given a new password since
+    // signing in no longer count.
+    let signed_in = session
+        .get::<(String, String)>(ADMIN_CREDENTIAL_KEY)
+        .unwrap_or_else(|e| {
+            log::warn!("Ignoring unreadable admin credential: {e}");
+            session.remove(ADMIN_CREDENTIAL_KEY);
+            None
+        });
+    let credential = signed_in.as_ref().and_then(|(name, fingerprint)| {
+        credentials
+            .get(name)
+            .filter(|credential| credential.fingerprint() == *fingerprint)
+            .cloned()
+    });
+    if signed_in.is_some() && credential.is_none() {
+        session.remove(ADMIN_CREDENTIAL_KEY);
+    }
+    AdminGrants::new(rooms, registry, everywhere, credential)
+}
+
+/// Drops the admin keys and credential the session picked up, for when
+/// somebody else takes it over.
+pub fn forget_admin_grants(session: &Session) {
+    session.remove(ADMIN_ROOMS_KEY);
+    session.remove(ADMIN_CREDENTIAL_KEY);
+}
+
 async fn index(
     session: Session,
-    poker: web::Data<Arc<crate::poker::PlaySession>>,
+    registry: web::Data<Arc<RoomRegistry>>,
     schema: web::Data<model::PokerSchema>,
+    credentials: web::Data<Arc<AdminCredentials>>,
+    oidc: Option<web::Data<Arc<Provider>>>,
     req: GraphQLRequest,
 ) -> GraphQLResponse {
     let req = req.into_inner();
     let identity = get_session_identity(&session);
-    let req = req.data(identity.clone());
+    let grants = Arc::new(get_admin_grants(
+        &session,
+        &registry,
+        &credentials,
+        oidc.as_deref().map(|p| &***p),
+    ));
+    let name_change = Arc::new(NameChange::default());
+    let req = req
+        .data(identity.clone())
+        .data(grants.clone())
+        .data(name_change.clone());
     let resp = schema.execute(req).await.into();
 
-    {
-        let poker = poker.into_inner();
-        let gstate = poker.game_state.lock().unwrap();
-        if let Some(player) = gstate.players.get(&identity.id) {
-            if &player.name != &identity.name {
-                log::debug!(
-                    "Player name change detected: id={} old name={} new name={}",
-                    &identity.id,
-                    &identity.name,
-                    &player.name,
-                );
-                if let Err(e) = session.insert("player_name", &player.name) {
-                    log::error!("{e}");
-                }
+    if let Some(rooms) = grants.take_changes() {
+        if let Err(e) = session.insert(ADMIN_ROOMS_KEY, rooms) {
+            log::error!("{e}");
+        }
+    }
+    match grants.take_credential_change() {
+        Some(Some(credential)) => {
+            let signed_in = (credential.name.clone(), credential.fingerprint());
+            if let Err(e) = session.insert(ADMIN_CREDENTIAL_KEY, signed_in) {
+                log::error!("{e}");
+            }
+        }
+        Some(None) => {
+            session.remove(ADMIN_CREDENTIAL_KEY);
+        }
+        None => {}
+    }
+
+    if let Some(name) = name_change.take() {
+        if name != identity.name {
+            log::debug!(
+                "Player name change detected: id={} old name={} new name={}",
+                &identity.id,
+                &identity.name,
+                &name,
+            );
+            if let Err(e) = session.insert("player_name", &name) {
+                log::error!("{e}");
             }
         }
     }
@@ -81,11 +178,28 @@ async fn index(
 }
 
 async fn index_ws(
+    session: Session,
+    registry: web::Data<Arc<RoomRegistry>>,
     schema: web::Data<model::PokerSchema>,
+    credentials: web::Data<Arc<AdminCredentials>>,
+    oidc: Option<web::Data<Arc<Provider>>>,
     req: HttpRequest,
     payload: web::Payload,
 ) -> Result<HttpResponse> {
-    GraphQLSubscription::new(Schema::clone(&*schema)).start(&req, payload)
+    // Lets subscriptions know who is on the other end of the socket. Admin
+    // grants changed over the socket only last as long as it does, since the
+    // cookie can't be updated once it's open.
+    let mut data = Data::default();
+    data.insert(get_session_identity(&session));
+    data.insert(Arc::new(get_admin_grants(
+        &session,
+        &registry,
+        &credentials,
+        oidc.as_deref().map(|p| &***p),
+    )));
+    GraphQLSubscription::new(Schema::clone(&*schema))
+        .with_data(data)
+        .start(&req, payload)
 }
 
 async fn index_playground() -> Result<HttpResponse> {
@@ -97,16 +211,16 @@ async fn index_playground() -> Result<HttpResponse> {
 }
 
 pub fn configure(cfg: &mut web::ServiceConfig) {
-    cfg.service(web::resource("/gql").guard(guard::Post()).to(index))
+    cfg.service(web::resource("/gql").guard(web_guard::Post()).to(index))
         .service(
             web::resource("/gql")
-                .guard(guard::Get())
-                .guard(guard::Header("upgrade", "websocket"))
+                .guard(web_guard::Get())
+                .guard(web_guard::Header("upgrade", "websocket"))
                 .to(index_ws),
         )
         .service(
             web::resource("/gql-playground")
-                .guard(guard::Get())
+                .guard(web_guard::Get())
                 .to(index_playground),
         );
 }
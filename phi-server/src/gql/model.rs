@@ -2,64 +2,926 @@
 //! design used for the websocket version, so I'm redefining a bunch of the
 //! types used for the game here.
 
-use crate::gql::SessionIdentity;
-use crate::poker::{AdminKey, PlayerId};
+use crate::credentials::AdminCredentials;
+use crate::gql::guard::{self, AdminGrants, AdminGuard, MemberGuard, OperatorGuard};
+use crate::gql::{NameChange, SessionIdentity, DEFAULT_PLAYER_NAME};
+use crate::poker::{AdminKey, BanId, PlaySession, PlayerId, RoundId, StoryId};
+use crate::rooms::{RoomId, RoomRegistry};
 use async_graphql::*;
+use chrono::{DateTime, Utc};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use tokio_stream::{self as stream, wrappers::BroadcastStream, Stream, StreamExt};
 
-/// Players who fail to send a heartbeat within this time will be shown as being idle.
-const PLAYER_IDLE_THRESHOLD: Duration = Duration::from_secs(30);
-
 pub type PokerSchema = Schema<Query, Mutation, Subscription>;
 
+/// Any longer and people would think it's broken.
+const MAX_AUTO_CALL_DELAY_MS: i32 = 60_000;
+/// A discussion running longer than an hour needs more than a timer.
+const MAX_TIMER_SECS: i32 = 60 * 60;
+/// Invites are for a sprint or so, not forever.
+const MAX_INVITE_SECS: i32 = 30 * 24 * 60 * 60;
+/// More than enough to page back through a busy day.
+const MAX_AUDIT_ENTRIES: i32 = crate::storage::MEMORY_AUDIT_ENTRIES as i32;
+
 #[derive(Clone, Debug, PartialEq, SimpleObject)]
 struct Player {
     pub id: PlayerId,
     /// The name displayed with the cards.
     pub name: String,
-    /// Index into the card data, `CARDS`.
+    /// Index into the cards of the deck for the room.
+    ///
+    /// Only shown to the player themselves until the cards are called.
     pub selected_card: Option<i32>,
+    pub has_voted: bool,
     pub idle: bool,
+    pub role: Role,
 }
 
-impl From<(&PlayerId, &crate::poker::Player)> for Player {
-    fn from((id, others): (&PlayerId, &crate::poker::Player)) -> Self {
+impl Player {
+    /// `visible` says whether the selected card can be shown, see
+    /// `Viewer::can_see()`.
+    fn new(session: &PlaySession, other: &crate::poker::Player, visible: bool) -> Self {
         Player {
-            id: *id,
-            name: others.name.clone(),
-            selected_card: others.selected_card.map(|n| n as i32),
-            idle: others.last_heartbeat.elapsed().unwrap() > PLAYER_IDLE_THRESHOLD,
+            id: other.id,
+            name: other.name.clone(),
+            selected_card: other.selected_card.filter(|_| visible).map(|n| n as i32),
+            has_voted: other.selected_card.is_some(),
+            idle: session.is_idle(other),
+            role: other.role.into(),
         }
     }
 }
 
-impl From<crate::poker::Player> for Player {
-    fn from(other: crate::poker::Player) -> Self {
-        Player {
+#[derive(Clone, Copy, Debug, Enum, Eq, PartialEq)]
+#[graphql(remote = "crate::poker::Role")]
+enum Role {
+    Voter,
+    /// Listens in without voting.
+    Spectator,
+    /// Runs the session, and votes along with everyone else.
+    Facilitator,
+}
+
+/// Keeps a player out of the room.
+#[derive(Clone, Debug, SimpleObject)]
+struct Ban {
+    pub id: BanId,
+    pub player_id: Option<PlayerId>,
+    pub name: Option<String>,
+    pub reason: Option<String>,
+    pub banned_at: DateTime<Utc>,
+}
+
+impl From<&crate::poker::Ban> for Ban {
+    fn from(other: &crate::poker::Ban) -> Self {
+        Ban {
+            id: other.id,
+            player_id: other.player_id,
+            name: other.name.clone(),
+            reason: other.reason.clone(),
+            banned_at: other.banned_at.into(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Enum, Eq, PartialEq)]
+#[graphql(remote = "crate::credentials::AdminRole")]
+enum AdminRole {
+    /// Runs games in the rooms listed for the credential.
+    Facilitator,
+    /// Runs games in any room, and can read the audit log.
+    ServerOperator,
+}
+
+/// A named admin credential, minus the password.
+#[derive(Clone, Debug, SimpleObject)]
+struct AdminCredential {
+    pub name: String,
+    pub role: AdminRole,
+}
+
+impl From<&crate::credentials::Credential> for AdminCredential {
+    fn from(other: &crate::credentials::Credential) -> Self {
+        AdminCredential {
+            name: other.name.clone(),
+            role: other.role.into(),
+        }
+    }
+}
+
+/// Something an admin did.
+#[derive(Clone, Debug, SimpleObject)]
+struct AuditEntry {
+    pub at: DateTime<Utc>,
+    /// The admin credential used, or `(admin key)` or `(admin group)` when
+    /// there wasn't one.
+    pub actor: String,
+    pub player_id: PlayerId,
+    pub player_name: String,
+    pub room_id: Option<RoomId>,
+    /// The mutation run.
+    pub action: String,
+    /// The player, story or ban acted on, if any.
+    pub target: Option<String>,
+    /// The arguments worth keeping and what came of them.
+    pub details: Option<String>,
+}
+
+impl From<crate::storage::AuditEntry> for AuditEntry {
+    fn from(other: crate::storage::AuditEntry) -> Self {
+        AuditEntry {
+            at: other.at.into(),
+            actor: other.actor,
+            player_id: other.player_id,
+            player_name: other.player_name,
+            room_id: other.room_id,
+            action: other.action,
+            target: other.target,
+            details: other.details,
+        }
+    }
+}
+
+/// Lets a player into a password protected room, or says why not.
+fn check_access(
+    session: &PlaySession,
+    password_hash: &str,
+    password: Option<&str>,
+    invite: Option<&str>,
+) -> Result<()> {
+    use crate::access::InviteError;
+    if let Some(invite) = invite {
+        return crate::access::check_invite(&session.admin_key, session.room_id, invite).map_err(
+            |e| match e {
+                InviteError::Expired => guard::coded("INVITE_EXPIRED", e.to_string()),
+                _ => guard::coded("INVALID_INVITE", e.to_string()),
+            },
+        );
+    }
+    match password {
+        Some(password) if crate::access::verify_password(password_hash, password.trim()) => Ok(()),
+        Some(_) => Err(guard::coded("WRONG_PASSWORD", "Wrong password.")),
+        None => Err(guard::coded(
+            "PASSWORD_REQUIRED",
+            "This room needs a password or an invite to join.",
+        )),
+    }
+}
+
+/// Whoever the game state is being shown to.
+#[derive(Clone, Copy, Debug, Default)]
+struct Viewer {
+    player_id: Option<PlayerId>,
+    is_admin: bool,
+}
+
+impl Viewer {
+    fn new(ctx: &Context<'_>, session: &PlaySession) -> Viewer {
+        Viewer {
+            player_id: ctx
+                .data_opt::<SessionIdentity>()
+                .map(|identity| identity.id),
+            is_admin: guard::is_admin(ctx, session.room_id),
+        }
+    }
+
+    /// Players always see their own card. Everyone else's stays hidden
+    /// until the call, and after it too in anonymous rooms unless the viewer
+    /// is an admin.
+    fn can_see(&self, game_state: &crate::poker::GameState, player_id: PlayerId) -> bool {
+        self.player_id == Some(player_id)
+            || (game_state.is_calling && (self.is_admin || !game_state.settings.anonymous))
+    }
+}
+
+/// Resolves the `room` argument (an id or slug) to the session for that room.
+fn room_session(ctx: &Context<'_>, room: &str) -> Result<Arc<PlaySession>> {
+    let registry = ctx.data_unchecked::<Arc<RoomRegistry>>();
+    registry
+        .get(room)
+        .map(|room| room.session.clone())
+        .ok_or_else(|| Error::new(format!("Unknown room: `{}`", room)))
+}
+
+/// The player a mutation acts on.
+///
+/// This is the caller unless `player_id` says otherwise, and only admins get
+/// to act on somebody else.
+fn acting_player(
+    ctx: &Context<'_>,
+    session: &PlaySession,
+    player_id: Option<PlayerId>,
+) -> Result<PlayerId> {
+    let caller = ctx.data_unchecked::<SessionIdentity>().id;
+    match player_id {
+        None => Ok(caller),
+        Some(id) if id == caller => Ok(id),
+        Some(id) if guard::is_admin(ctx, session.room_id) => Ok(id),
+        Some(id) => Err(guard::forbidden(format!(
+            "Only admins can act on behalf of player `{}`.",
+            id
+        ))),
+    }
+}
+
+/// Records a mutation in the audit log when an admin ran it for somebody
+/// else. See `acting_player`.
+fn audit_on_behalf(
+    ctx: &Context<'_>,
+    session: &PlaySession,
+    player_id: PlayerId,
+    details: impl Into<String>,
+) {
+    if ctx.data_unchecked::<SessionIdentity>().id != player_id {
+        guard::audit(
+            ctx,
+            Some(session.room_id),
+            Some(player_id.to_string()),
+            details,
+        );
+    }
+}
+
+#[derive(Clone, Debug, SimpleObject)]
+struct Room {
+    pub id: RoomId,
+    /// Human friendly handle used in urls.
+    pub slug: String,
+    pub name: String,
+    /// Players need the password or an invite to join.
+    pub password_protected: bool,
+}
+
+impl From<&crate::rooms::Room> for Room {
+    fn from(other: &crate::rooms::Room) -> Self {
+        Room {
+            id: other.id,
+            slug: other.slug.clone(),
+            name: other.name.clone(),
+            password_protected: other
+                .session
+                .read(|game_state| game_state.settings.password_hash.is_some())
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// Lets whoever holds the token into a password protected room.
+#[derive(Clone, Debug, SimpleObject)]
+struct Invite {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Returned to whoever creates a room, since they are the only one who gets to
+/// see the admin key.
+#[derive(Clone, Debug, SimpleObject)]
+struct CreatedRoom {
+    pub room: Room,
+    pub admin_key: AdminKey,
+}
+
+#[derive(Clone, Copy, Debug, Enum, Eq, PartialEq)]
+#[graphql(remote = "crate::deck::CardKind")]
+enum CardKind {
+    /// An actual estimate.
+    Numeric,
+    /// "I don't know."
+    Unknown,
+    /// "This is too big to estimate."
+    Infinite,
+    /// "I need a break."
+    Break,
+}
+
+#[derive(Clone, Debug, SimpleObject)]
+struct Card {
+    /// What's shown on the card.
+    pub label: String,
+    /// What the card is worth, for numeric cards.
+    pub value: Option<f64>,
+    pub kind: CardKind,
+}
+
+impl From<&crate::deck::Card> for Card {
+    fn from(other: &crate::deck::Card) -> Self {
+        Card {
+            label: other.label.clone(),
+            value: other.value,
+            kind: other.kind.into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, SimpleObject)]
+struct Deck {
+    pub name: String,
+    pub cards: Vec<Card>,
+}
+
+impl From<&crate::deck::Deck> for Deck {
+    fn from(other: &crate::deck::Deck) -> Self {
+        Deck {
+            name: other.name.clone(),
+            cards: other.cards.iter().map(Card::from).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, SimpleObject)]
+struct CardCount {
+    /// Index into the cards of the deck for the room.
+    pub card_index: i32,
+    pub card: Card,
+    pub count: i32,
+}
+
+#[derive(Clone, Copy, Debug, Enum, Eq, PartialEq)]
+enum ConsensusRuleKind {
+    /// Everybody picked the same card.
+    Unanimous,
+    /// Every vote is on the same card or a neighbouring one.
+    Adjacent,
+    /// At least `percent` of the votes are on the same card.
+    Supermajority,
+}
+
+#[derive(Clone, Debug, SimpleObject)]
+struct ConsensusRule {
+    pub kind: ConsensusRuleKind,
+    pub percent: Option<i32>,
+}
+
+/// Calls the cards once every active player has voted.
+#[derive(Clone, Debug, SimpleObject)]
+struct AutoCall {
+    pub enabled: bool,
+    /// How long to wait before calling. A vote changing in the meantime
+    /// starts the wait over.
+    pub delay_ms: i32,
+}
+
+impl From<Option<crate::poker::AutoCall>> for AutoCall {
+    fn from(other: Option<crate::poker::AutoCall>) -> Self {
+        AutoCall {
+            enabled: other.is_some(),
+            delay_ms: other
+                .map(|auto_call| auto_call.delay_ms as i32)
+                .unwrap_or(0),
+        }
+    }
+}
+
+impl From<crate::poker::ConsensusRule> for ConsensusRule {
+    fn from(other: crate::poker::ConsensusRule) -> Self {
+        use crate::poker::ConsensusRule::*;
+        match other {
+            Unanimous => ConsensusRule {
+                kind: ConsensusRuleKind::Unanimous,
+                percent: None,
+            },
+            Adjacent => ConsensusRule {
+                kind: ConsensusRuleKind::Adjacent,
+                percent: None,
+            },
+            Supermajority { percent } => ConsensusRule {
+                kind: ConsensusRuleKind::Supermajority,
+                percent: Some(percent as i32),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug, SimpleObject)]
+struct Consensus {
+    pub rule: ConsensusRule,
+    pub reached: bool,
+    /// The card to go with. When there's no consensus this is a best guess
+    /// based on the mean.
+    pub suggested_card: Option<Card>,
+    pub suggested_card_index: Option<i32>,
+}
+
+/// Vote statistics, worked out from the numeric value of each card.
+#[derive(Clone, Debug, SimpleObject)]
+struct Results {
+    /// How many players have a card selected, of any kind.
+    pub vote_count: i32,
+    /// Counts for every card that was picked at least once, in deck order.
+    pub distribution: Vec<CardCount>,
+    pub mean: Option<f64>,
+    pub median: Option<f64>,
+    /// The most picked numeric cards. More than one when there's a tie.
+    pub mode: Vec<Card>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub standard_deviation: Option<f64>,
+    /// The numeric card closest to the mean.
+    pub nearest_card: Option<Card>,
+    /// How many players picked "?".
+    pub unknown_count: i32,
+    pub infinite_count: i32,
+    /// How many players picked "☕".
+    pub break_count: i32,
+    pub consensus: Consensus,
+}
+
+impl Results {
+    fn new(deck: &crate::deck::Deck, other: crate::stats::Results) -> Self {
+        let card = |idx: usize| Card::from(&deck.cards[idx]);
+        Results {
+            vote_count: other.vote_count as i32,
+            distribution: other
+                .distribution
+                .iter()
+                .map(|c| CardCount {
+                    card_index: c.card as i32,
+                    card: card(c.card),
+                    count: c.count as i32,
+                })
+                .collect(),
+            mean: other.mean,
+            median: other.median,
+            mode: other.mode.into_iter().map(card).collect(),
+            min: other.min,
+            max: other.max,
+            standard_deviation: other.standard_deviation,
+            nearest_card: other.nearest_card.map(card),
+            unknown_count: other.unknown_count as i32,
+            infinite_count: other.infinite_count as i32,
+            break_count: other.break_count as i32,
+            consensus: Consensus {
+                rule: other.consensus.rule.into(),
+                reached: other.consensus.reached,
+                suggested_card: other.consensus.suggested_card.map(card),
+                suggested_card_index: other.consensus.suggested_card.map(|n| n as i32),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Enum, Eq, PartialEq)]
+#[graphql(remote = "crate::poker::StoryStatus")]
+enum StoryStatus {
+    /// Waiting its turn in the queue.
+    Pending,
+    /// The story the table is voting on right now.
+    Estimating,
+    Estimated,
+    Skipped,
+}
+
+/// Something to be estimated, like a ticket from the issue tracker.
+#[derive(Clone, Debug, SimpleObject)]
+struct Story {
+    pub id: StoryId,
+    pub title: String,
+    pub description: Option<String>,
+    /// Where to read more, like a link to the ticket.
+    pub link: Option<String>,
+    pub status: StoryStatus,
+    /// The estimate the facilitator accepted.
+    pub estimate: Option<Card>,
+}
+
+impl From<&crate::poker::Story> for Story {
+    fn from(other: &crate::poker::Story) -> Self {
+        Story {
+            id: other.id,
+            title: other.title.clone(),
+            description: other.description.clone(),
+            link: other.link.clone(),
+            status: other.status.into(),
+            estimate: other.estimate.as_ref().map(Card::from),
+        }
+    }
+}
+
+/// What a player had picked when a round ended.
+#[derive(Clone, Debug, SimpleObject)]
+struct RoundVote {
+    /// Hidden for rounds played while the room was anonymous, except from
+    /// admins.
+    pub player_id: Option<PlayerId>,
+    /// The name of the player at the time. Hidden along with `player_id`.
+    pub name: Option<String>,
+    /// Empty for players who sat at the table without voting.
+    pub card: Option<Card>,
+}
+
+/// A completed call/reset cycle.
+#[derive(Clone, Debug, SimpleObject)]
+struct Round {
+    pub id: RoundId,
+    pub story_id: Option<StoryId>,
+    pub story_title: Option<String>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub called_at: Option<DateTime<Utc>>,
+    pub ended_at: DateTime<Utc>,
+    /// Time from the start of the round until it was called.
+    pub duration_secs: Option<f64>,
+    pub votes: Vec<RoundVote>,
+    pub final_estimate: Option<Card>,
+}
+
+impl From<&crate::poker::Round> for Round {
+    fn from(other: &crate::poker::Round) -> Self {
+        let duration_secs = match (other.started_at, other.called_at) {
+            (Some(started), Some(called)) => {
+                called.duration_since(started).ok().map(|d| d.as_secs_f64())
+            }
+            _ => None,
+        };
+        Round {
             id: other.id,
-            name: other.name,
-            selected_card: other.selected_card.map(|n| n as i32),
-            idle: other.last_heartbeat.elapsed().unwrap() > PLAYER_IDLE_THRESHOLD,
+            story_id: other.story_id,
+            story_title: other.story_title.clone(),
+            started_at: other.started_at.map(Into::into),
+            called_at: other.called_at.map(Into::into),
+            ended_at: other.ended_at.into(),
+            duration_secs,
+            votes: other
+                .votes
+                .iter()
+                .map(|vote| RoundVote {
+                    player_id: Some(vote.player_id),
+                    name: Some(vote.name.clone()),
+                    card: vote.card.as_ref().map(Card::from),
+                })
+                .collect(),
+            final_estimate: other.final_estimate.as_ref().map(Card::from),
+        }
+    }
+}
+
+/// A page of the round history, newest first.
+#[derive(Clone, Debug, SimpleObject)]
+struct RoundPage {
+    /// How many rounds there are in total.
+    pub total: i32,
+    pub rounds: Vec<Round>,
+}
+
+#[derive(Clone, Copy, Debug, Enum, Eq, PartialEq)]
+#[graphql(remote = "crate::poker::TimerExpiry")]
+enum TimerExpiry {
+    /// The cards are called.
+    Call,
+    /// The timer is marked as expired and the facilitator decides what to do.
+    Flag,
+}
+
+/// A countdown for the round in progress.
+#[derive(Clone, Debug, SimpleObject)]
+struct RoundTimer {
+    pub started_at: DateTime<Utc>,
+    pub deadline: DateTime<Utc>,
+    /// As of when this was sent. Clients should count down from `deadline`
+    /// with their own clock.
+    pub remaining_secs: i32,
+    pub on_expiry: TimerExpiry,
+    pub expired: bool,
+}
+
+impl From<&crate::poker::RoundTimer> for RoundTimer {
+    fn from(other: &crate::poker::RoundTimer) -> Self {
+        RoundTimer {
+            started_at: other.started_at.into(),
+            deadline: other.deadline.into(),
+            remaining_secs: other.remaining().as_secs_f64().ceil() as i32,
+            on_expiry: other.on_expiry.into(),
+            expired: other.expired,
         }
     }
 }
 
-struct GameState;
+/// A point-in-time view of the game state for a room.
+struct GameState {
+    state: crate::poker::GameState,
+    deck: Arc<crate::deck::Deck>,
+    players: Vec<Player>,
+}
+
+impl GameState {
+    /// Card selections are hidden as `viewer` requires.
+    fn load(session: &PlaySession, viewer: Viewer) -> Result<GameState> {
+        let state = session.read(crate::poker::GameState::without_history)?;
+        let deck = session.deck(&state);
+        let players = state
+            .players
+            .values()
+            .map(|player| Player::new(session, player, viewer.can_see(&state, player.id)))
+            .collect();
+        Ok(GameState {
+            state,
+            deck,
+            players,
+        })
+    }
+}
 
 #[Object]
 impl GameState {
-    async fn is_calling(&self, ctx: &Context<'_>) -> bool {
-        let session = ctx.data_unchecked::<Arc<crate::poker::PlaySession>>();
-        let game_state = session.game_state.lock().unwrap();
-        game_state.is_calling
+    async fn is_calling(&self) -> bool {
+        self.state.is_calling
+    }
+
+    /// Everybody who votes. Spectators are listed separately.
+    async fn players(&self) -> Vec<Player> {
+        self.players
+            .iter()
+            .filter(|player| player.role != Role::Spectator)
+            .cloned()
+            .collect()
+    }
+
+    async fn spectators(&self) -> Vec<Player> {
+        self.players
+            .iter()
+            .filter(|player| player.role == Role::Spectator)
+            .cloned()
+            .collect()
+    }
+
+    /// Active voters yet to pick a card. Empty once the cards are called.
+    async fn waiting_on(&self) -> Vec<Player> {
+        if self.state.is_calling {
+            return vec![];
+        }
+        self.players
+            .iter()
+            .filter(|player| player.role != Role::Spectator && !player.idle && !player.has_voted)
+            .cloned()
+            .collect()
+    }
+
+    /// The deck in play. Card selections index into this.
+    async fn deck(&self) -> Deck {
+        Deck::from(&*self.deck)
+    }
+
+    /// The queue of stories to estimate, in order.
+    async fn stories(&self) -> Vec<Story> {
+        self.state.stories.iter().map(Story::from).collect()
+    }
+
+    /// The story being voted on, if any.
+    async fn current_story(&self) -> Option<Story> {
+        self.state.current_story().map(Story::from)
+    }
+
+    async fn timer(&self) -> Option<RoundTimer> {
+        self.state.timer.as_ref().map(RoundTimer::from)
+    }
+
+    async fn consensus_rule(&self) -> ConsensusRule {
+        self.state.settings.consensus_rule.into()
     }
 
-    async fn players(&self, ctx: &Context<'_>) -> Vec<Player> {
-        let session = ctx.data_unchecked::<Arc<crate::poker::PlaySession>>();
-        let game_state = session.game_state.lock().unwrap();
-        game_state.players.iter().map(Into::into).collect()
+    /// Players need the password or an invite to join.
+    async fn password_protected(&self) -> bool {
+        self.state.settings.password_hash.is_some()
+    }
+
+    /// Whether card selections stay hidden after the call.
+    async fn anonymous(&self) -> bool {
+        self.state.settings.anonymous
+    }
+
+    async fn auto_call(&self) -> AutoCall {
+        self.state.settings.auto_call.into()
+    }
+
+    /// Only available while calling, so nobody gets a sneak peek.
+    async fn results(&self) -> Option<Results> {
+        if !self.state.is_calling {
+            return None;
+        }
+        let results = crate::stats::Results::compute(&self.deck, &self.state);
+        Some(Results::new(&self.deck, results))
+    }
+}
+
+#[derive(Clone, Debug, SimpleObject)]
+struct PlayerJoined {
+    sequence: u64,
+    player: Player,
+}
+
+#[derive(Clone, Debug, SimpleObject)]
+struct PlayerLeft {
+    sequence: u64,
+    player_id: PlayerId,
+}
+
+#[derive(Clone, Debug, SimpleObject)]
+struct PlayerRenamed {
+    sequence: u64,
+    player_id: PlayerId,
+    name: String,
+}
+
+#[derive(Clone, Debug, SimpleObject)]
+struct PlayerRoleChanged {
+    sequence: u64,
+    player_id: PlayerId,
+    role: Role,
+}
+
+/// The player went away or came back, going by their open sockets and
+/// heartbeats.
+#[derive(Clone, Debug, SimpleObject)]
+struct PlayerIdleChanged {
+    sequence: u64,
+    player_id: PlayerId,
+    idle: bool,
+}
+
+/// An admin took a player out of the room. Follows the `PlayerLeft` event
+/// when the player was still in it.
+#[derive(Clone, Debug, SimpleObject)]
+struct Kicked {
+    sequence: u64,
+    player_id: PlayerId,
+    /// Only shared with the player kicked, and with admins.
+    reason: Option<String>,
+    /// Whether registering again will fail.
+    banned: bool,
+}
+
+/// Says whether the player has a card selected, but not which one.
+#[derive(Clone, Debug, SimpleObject)]
+struct PlayerVoted {
+    sequence: u64,
+    player_id: PlayerId,
+    has_card: bool,
+}
+
+#[derive(Clone, Debug, SimpleObject)]
+struct Called {
+    sequence: u64,
+    results: Results,
+}
+
+#[derive(Clone, Debug, SimpleObject)]
+struct Resumed {
+    sequence: u64,
+}
+
+/// The round started over and every selection was cleared.
+#[derive(Clone, Debug, SimpleObject)]
+struct Reset {
+    sequence: u64,
+}
+
+#[derive(Clone, Debug, SimpleObject)]
+struct TimerStarted {
+    sequence: u64,
+    timer: RoundTimer,
+}
+
+#[derive(Clone, Debug, SimpleObject)]
+struct TimerWarning {
+    sequence: u64,
+    remaining_secs: i32,
+}
+
+#[derive(Clone, Debug, SimpleObject)]
+struct TimerExpired {
+    sequence: u64,
+}
+
+#[derive(Clone, Debug, SimpleObject)]
+struct TimerCancelled {
+    sequence: u64,
+}
+
+/// The whole queue, since one change can move several stories around.
+#[derive(Clone, Debug, SimpleObject)]
+struct StoryChanged {
+    sequence: u64,
+    stories: Vec<Story>,
+    current_story: Option<StoryId>,
+}
+
+/// The deck in play or one of the room settings changed. Changing the deck
+/// also resets the round.
+#[derive(Clone, Debug, SimpleObject)]
+struct SettingsChanged {
+    sequence: u64,
+    deck: Deck,
+    consensus_rule: ConsensusRule,
+    anonymous: bool,
+    auto_call: AutoCall,
+    password_protected: bool,
+}
+
+#[derive(Clone, Debug, Union)]
+enum GameEvent {
+    PlayerJoined(PlayerJoined),
+    PlayerLeft(PlayerLeft),
+    PlayerRenamed(PlayerRenamed),
+    PlayerRoleChanged(PlayerRoleChanged),
+    PlayerIdleChanged(PlayerIdleChanged),
+    PlayerVoted(PlayerVoted),
+    Called(Box<Called>),
+    Resumed(Resumed),
+    Reset(Reset),
+    TimerStarted(TimerStarted),
+    TimerWarning(TimerWarning),
+    TimerExpired(TimerExpired),
+    TimerCancelled(TimerCancelled),
+    StoryChanged(StoryChanged),
+    SettingsChanged(Box<SettingsChanged>),
+    Kicked(Kicked),
+}
+
+impl GameEvent {
+    fn new(
+        session: &PlaySession,
+        viewer: Viewer,
+        sequence: u64,
+        other: crate::poker::GameEvent,
+    ) -> Self {
+        use crate::poker::GameEvent as Event;
+        match other {
+            Event::PlayerJoined(player) => GameEvent::PlayerJoined(PlayerJoined {
+                sequence,
+                player: Player::new(session, &player, false),
+            }),
+            Event::PlayerLeft(player_id) => GameEvent::PlayerLeft(PlayerLeft {
+                sequence,
+                player_id,
+            }),
+            Event::PlayerRenamed(player_id, name) => GameEvent::PlayerRenamed(PlayerRenamed {
+                sequence,
+                player_id,
+                name,
+            }),
+            Event::PlayerRoleChanged(player_id, role) => {
+                GameEvent::PlayerRoleChanged(PlayerRoleChanged {
+                    sequence,
+                    player_id,
+                    role: role.into(),
+                })
+            }
+            Event::PlayerIdleChanged(player_id, idle) => {
+                GameEvent::PlayerIdleChanged(PlayerIdleChanged {
+                    sequence,
+                    player_id,
+                    idle,
+                })
+            }
+            Event::PlayerVoted(player_id, has_card) => GameEvent::PlayerVoted(PlayerVoted {
+                sequence,
+                player_id,
+                has_card,
+            }),
+            Event::Called(deck, results) => GameEvent::Called(Box::new(Called {
+                sequence,
+                results: Results::new(&deck, results),
+            })),
+            Event::Resumed => GameEvent::Resumed(Resumed { sequence }),
+            Event::Reset => GameEvent::Reset(Reset { sequence }),
+            Event::TimerStarted(timer) => GameEvent::TimerStarted(TimerStarted {
+                sequence,
+                timer: RoundTimer::from(&timer),
+            }),
+            Event::TimerWarning(remaining) => GameEvent::TimerWarning(TimerWarning {
+                sequence,
+                remaining_secs: remaining.as_secs() as i32,
+            }),
+            Event::TimerExpired => GameEvent::TimerExpired(TimerExpired { sequence }),
+            Event::TimerCancelled => GameEvent::TimerCancelled(TimerCancelled { sequence }),
+            Event::StoryChanged {
+                stories,
+                current_story,
+            } => GameEvent::StoryChanged(StoryChanged {
+                sequence,
+                stories: stories.iter().map(Story::from).collect(),
+                current_story,
+            }),
+            Event::SettingsChanged(deck, settings) => {
+                GameEvent::SettingsChanged(Box::new(SettingsChanged {
+                    sequence,
+                    deck: Deck::from(&*deck),
+                    consensus_rule: settings.consensus_rule.into(),
+                    anonymous: settings.anonymous,
+                    auto_call: settings.auto_call.into(),
+                    password_protected: settings.password_hash.is_some(),
+                }))
+            }
+            Event::Kicked(kick) => {
+                let can_see_reason = viewer.is_admin || viewer.player_id == Some(kick.player_id);
+                GameEvent::Kicked(Kicked {
+                    sequence,
+                    player_id: kick.player_id,
+                    reason: kick.reason.filter(|_| can_see_reason),
+                    banned: kick.banned,
+                })
+            }
+        }
     }
 }
 
@@ -67,13 +929,117 @@ pub struct Query;
 
 #[Object]
 impl Query {
-    async fn cards(&self, ctx: &Context<'_>) -> &[&str] {
-        let session = ctx.data_unchecked::<Arc<crate::poker::PlaySession>>();
-        session.deck
+    async fn rooms(&self, ctx: &Context<'_>) -> Vec<Room> {
+        let registry = ctx.data_unchecked::<Arc<RoomRegistry>>();
+        registry
+            .list()
+            .iter()
+            .map(|room| Room::from(&**room))
+            .collect()
     }
 
-    async fn game_state(&self) -> GameState {
-        GameState
+    /// Look up a room by id or slug.
+    async fn room(&self, ctx: &Context<'_>, room: String) -> Option<Room> {
+        let registry = ctx.data_unchecked::<Arc<RoomRegistry>>();
+        registry.get(&room).map(|room| Room::from(&*room))
+    }
+
+    /// Every deck a room can switch to.
+    async fn decks(&self, ctx: &Context<'_>) -> Vec<Deck> {
+        let registry = ctx.data_unchecked::<Arc<RoomRegistry>>();
+        registry
+            .decks()
+            .list()
+            .iter()
+            .map(|deck| Deck::from(&**deck))
+            .collect()
+    }
+
+    /// The cards in the deck currently in play for the room.
+    #[graphql(guard = "MemberGuard::new(&room)")]
+    async fn cards(&self, ctx: &Context<'_>, room: String) -> Result<Vec<Card>> {
+        let session = room_session(ctx, &room)?;
+        Ok(session
+            .read(|game_state| session.deck(game_state))?
+            .cards
+            .iter()
+            .map(Card::from)
+            .collect())
+    }
+
+    #[graphql(guard = "MemberGuard::new(&room)")]
+    async fn game_state(&self, ctx: &Context<'_>, room: String) -> Result<GameState> {
+        let session = room_session(ctx, &room)?;
+        GameState::load(&session, Viewer::new(ctx, &session))
+    }
+
+    /// Players kept out of the room, oldest ban first.
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn bans(&self, ctx: &Context<'_>, room: String) -> Result<Vec<Ban>> {
+        let session = room_session(ctx, &room)?;
+        Ok(session.read(|game_state| game_state.bans.iter().map(Ban::from).collect())?)
+    }
+
+    /// The admin credential the client is signed in with.
+    async fn admin_credential(&self, ctx: &Context<'_>) -> Option<AdminCredential> {
+        ctx.data_opt::<Arc<AdminGrants>>()
+            .and_then(|grants| grants.credential())
+            .map(|credential| AdminCredential::from(&credential))
+    }
+
+    /// What admins have been up to, newest first.
+    #[graphql(guard = "OperatorGuard")]
+    async fn audit_log(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = 100)] limit: i32,
+    ) -> Result<Vec<AuditEntry>> {
+        if !(1..=MAX_AUDIT_ENTRIES).contains(&limit) {
+            return Err(Error::new(format!(
+                "The limit needs to be between 1 and {}.",
+                MAX_AUDIT_ENTRIES
+            )));
+        }
+        let registry = ctx.data_unchecked::<Arc<RoomRegistry>>();
+        Ok(registry
+            .audit_log(limit as usize)?
+            .into_iter()
+            .map(AuditEntry::from)
+            .collect())
+    }
+
+    /// The rounds played in the room, newest first.
+    #[graphql(guard = "MemberGuard::new(&room)")]
+    async fn rounds(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        #[graphql(default = 0)] offset: i32,
+        #[graphql(default = 20)] limit: i32,
+    ) -> Result<RoundPage> {
+        let session = room_session(ctx, &room)?;
+        let is_admin = guard::is_admin(ctx, session.room_id);
+        Ok(session.read(|game_state| RoundPage {
+            total: game_state.rounds.len() as i32,
+            rounds: game_state
+                .rounds
+                .iter()
+                .rev()
+                .skip(offset.max(0) as usize)
+                .take(limit.clamp(0, 100) as usize)
+                .map(|round| {
+                    let anonymous = round.anonymous.unwrap_or(game_state.settings.anonymous);
+                    let mut round = Round::from(round);
+                    if anonymous && !is_admin {
+                        for vote in &mut round.votes {
+                            vote.player_id = None;
+                            vote.name = None;
+                        }
+                    }
+                    round
+                })
+                .collect(),
+        })?)
     }
 }
 
@@ -81,164 +1047,1460 @@ pub struct Mutation;
 
 #[Object]
 impl Mutation {
-    async fn register(&self, ctx: &Context<'_>) -> Result<PlayerId> {
-        let session = ctx.data_unchecked::<Arc<crate::poker::PlaySession>>();
+    /// Opens a new room. The admin key for the room is only ever returned here.
+    async fn create_room(
+        &self,
+        ctx: &Context<'_>,
+        slug: Option<String>,
+        name: Option<String>,
+        deck: Option<String>,
+    ) -> Result<CreatedRoom> {
+        let registry = ctx.data_unchecked::<Arc<RoomRegistry>>();
+        let room = registry
+            .create(slug, name, None, deck)
+            .map_err(|e| Error::new(e.to_string()))?;
+        Ok(CreatedRoom {
+            room: Room::from(&*room),
+            admin_key: room.session.admin_key.clone(),
+        })
+    }
+
+    /// Deletes the room along with its game state and history. Everybody in
+    /// it is left without a room. The default room can't be deleted.
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn delete_room(&self, ctx: &Context<'_>, room: String) -> Result<bool> {
+        let registry = ctx.data_unchecked::<Arc<RoomRegistry>>();
+        let removed = registry
+            .remove(&room)
+            .map_err(|e| Error::new(e.to_string()))?;
+        if let Some(removed) = &removed {
+            guard::audit(ctx, Some(removed.id), Some(removed.slug.clone()), "");
+        }
+        Ok(removed.is_some())
+    }
+
+    /// Joins the room, as a voter unless another `role` is picked.
+    ///
+    /// Password protected rooms need the `password` or an `invite` token.
+    /// Getting either wrong fails with a `code` of `PASSWORD_REQUIRED`,
+    /// `WRONG_PASSWORD`, `INVALID_INVITE` or `INVITE_EXPIRED`.
+    async fn register(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        #[graphql(default_with = "Role::Voter")] role: Role,
+        password: Option<String>,
+        invite: Option<String>,
+    ) -> Result<PlayerId> {
+        let session = room_session(ctx, &room)?;
         let SessionIdentity { name, id } = ctx.data_unchecked::<SessionIdentity>().clone();
-        let player = crate::poker::Player::new(name.clone(), id.clone());
-        {
-            let mut game_state = session.game_state.lock().unwrap();
-            game_state.players.insert(player.id, player);
+        // Players already in the room got past this the first time.
+        let password_hash = session.read(|game_state| {
+            game_state
+                .settings
+                .password_hash
+                .clone()
+                .filter(|_| !game_state.players.contains_key(&id))
+        })?;
+        if let Some(hash) = &password_hash {
+            check_access(&session, hash, password.as_deref(), invite.as_deref())?;
         }
+        let player = crate::poker::Player::new(name.clone(), id, role.into());
+        session.update_and_emit(|game_state, events| {
+            if game_state.ban_for(player.id, &player.name).is_some() {
+                return Err(guard::forbidden("You have been banned from this room."));
+            }
+            match game_state.players.insert(player.id, player.clone()) {
+                None => events.push(crate::poker::GameEvent::PlayerJoined(player)),
+                Some(prev) => {
+                    if prev.role != player.role {
+                        events.push(crate::poker::GameEvent::PlayerRoleChanged(
+                            player.id,
+                            player.role,
+                        ));
+                    }
+                    // Registering again starts the player over without a card.
+                    if prev.selected_card.is_some() {
+                        events.push(crate::poker::GameEvent::PlayerVoted(player.id, false));
+                    }
+                }
+            }
+            Ok(())
+        })??;
         session.notify_subscribers();
         Ok(id)
     }
 
+    /// Changes how a player takes part. Spectators lose their card.
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn set_player_role(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        player_id: PlayerId,
+        role: Role,
+    ) -> Result<Player> {
+        let session = room_session(ctx, &room)?;
+        let viewer = Viewer::new(ctx, &session);
+        let player = session.update_and_emit(|game_state, events| {
+            let is_calling = game_state.is_calling;
+            let visible = viewer.can_see(game_state, player_id);
+            let player = game_state
+                .players
+                .get_mut(&player_id)
+                .ok_or_else(|| Error::new(format!("Unknown player: `{}`", player_id)))?;
+            player.role = role.into();
+            if !player.role.votes() && !is_calling {
+                player.selected_card = None;
+            }
+            events.push(crate::poker::GameEvent::PlayerRoleChanged(
+                player_id,
+                role.into(),
+            ));
+            Ok::<_, Error>(Player::new(&session, player, visible))
+        })??;
+        session.notify_subscribers();
+        // One less voter to wait for.
+        session.maybe_auto_call();
+        guard::audit(
+            ctx,
+            Some(session.room_id),
+            Some(player_id.to_string()),
+            format!("role: {:?}", role),
+        );
+        Ok(player)
+    }
+
     /// Clients that want admin privileges send their key.
     /// The bool return is for if the keys match or not.
-    async fn admin_challenge(&self, ctx: &Context<'_>, key: AdminKey) -> Result<bool> {
-        let session = ctx.data_unchecked::<Arc<crate::poker::PlaySession>>();
-        Ok(session.admin_key == key)
+    ///
+    /// A match grants admin privileges in the room for the rest of the
+    /// session, or until the admin key for the room changes. A mismatch takes
+    /// them away.
+    async fn admin_challenge(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        key: AdminKey,
+    ) -> Result<bool> {
+        let session = room_session(ctx, &room)?;
+        let grants = ctx
+            .data_opt::<Arc<AdminGrants>>()
+            .ok_or_else(|| Error::new("Admin challenges are not supported over websockets."))?;
+        let matched = session.admin_key == key;
+        if matched {
+            grants.grant(session.room_id, &session.admin_key);
+            guard::audit(ctx, Some(session.room_id), None, "");
+        } else {
+            grants.revoke(session.room_id);
+        }
+        Ok(matched)
     }
 
-    async fn heartbeat(&self, ctx: &Context<'_>, player_id: PlayerId) -> Result<bool> {
-        let session = ctx.data_unchecked::<Arc<crate::poker::PlaySession>>();
-        {
-            let mut state = session.game_state.lock().unwrap();
-            if let Some(player) = state.players.get_mut(&player_id) {
-                player.last_heartbeat = SystemTime::now();
-            } else {
-                log::warn!(
-                    "Tried to update heartbeat for unknown player: `{}`",
-                    player_id
-                );
-            }
-            let prev_players = state.players.clone();
+    /// Signs in with a named admin credential, which grants admin privileges
+    /// in the rooms it runs for the rest of the session. A wrong name or
+    /// password fails with a `code` of `WRONG_CREDENTIALS`.
+    async fn admin_sign_in(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        password: String,
+    ) -> Result<AdminCredential> {
+        let grants = ctx
+            .data_opt::<Arc<AdminGrants>>()
+            .ok_or_else(|| Error::new("Signing in is not supported over websockets."))?;
+        let credentials = ctx.data_unchecked::<Arc<AdminCredentials>>();
+        let credential = credentials
+            .verify(&name, &password)
+            .ok_or_else(|| guard::coded("WRONG_CREDENTIALS", "Wrong name or password."))?;
+        grants.set_credential(Some(credential.clone()));
+        guard::audit(ctx, None, None, "");
+        Ok(AdminCredential::from(credential))
+    }
 
-            state
-                .players
-                .retain(|_k, v| v.last_heartbeat.elapsed().unwrap() < session.disconnect_timeout);
-            if state.players != prev_players {
-                log::warn!(
-                    "removing idle players: {}",
-                    prev_players.len() - state.players.len()
-                );
-                session.notify_subscribers();
-            }
+    /// Signs out of the admin credential. Admin keys passed before signing
+    /// in still count.
+    async fn admin_sign_out(&self, ctx: &Context<'_>) -> Result<bool> {
+        let grants = ctx
+            .data_opt::<Arc<AdminGrants>>()
+            .ok_or_else(|| Error::new("Signing out is not supported over websockets."))?;
+        if grants.credential().is_none() {
+            return Ok(false);
+        }
+        guard::audit(ctx, None, None, "");
+        grants.set_credential(None);
+        Ok(true)
+    }
+
+    /// Keeps the player from being shown as idle, for clients that don't
+    /// hold a `gameState` subscription open.
+    async fn heartbeat(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        player_id: Option<PlayerId>,
+    ) -> Result<bool> {
+        let session = room_session(ctx, &room)?;
+        let player_id = acting_player(ctx, &session, player_id)?;
+        // Idle players are swept out by the reaper for the room.
+        if !session.heartbeat(player_id)? {
+            log::warn!(
+                "Tried to update heartbeat for unknown player: `{}`",
+                player_id
+            );
         }
+        audit_on_behalf(ctx, &session, player_id, "");
         Ok(true)
     }
 
     async fn set_player_name(
         &self,
         ctx: &Context<'_>,
-        player_id: PlayerId,
+        room: String,
+        player_id: Option<PlayerId>,
         name: String,
     ) -> Result<Option<Player>> {
-        let session = ctx.data_unchecked::<Arc<crate::poker::PlaySession>>();
-        let outcome = {
-            let mut game_state = session.game_state.lock().unwrap();
+        let session = room_session(ctx, &room)?;
+        let player_id = acting_player(ctx, &session, player_id)?;
+        let viewer = Viewer::new(ctx, &session);
+        let outcome = session.update_and_emit(|game_state, events| {
+            let visible = viewer.can_see(game_state, player_id);
+            if game_state.ban_for(player_id, &name).is_some() {
+                return Err(guard::forbidden(
+                    "That name or player has been banned from this room.",
+                ));
+            }
             if let Some(player) = game_state.players.get_mut(&player_id) {
                 player.name = name;
-                Ok(Some(Player::from(player.clone())))
+                events.push(crate::poker::GameEvent::PlayerRenamed(
+                    player.id,
+                    player.name.clone(),
+                ));
+                Ok(Some(Player::new(&session, player, visible)))
             } else {
                 log::warn!("Tried to update name for unknown player: `{}`", player_id);
                 Ok(None)
             }
-        };
+        })??;
         session.notify_subscribers();
-        outcome
+        if let Some(player) = &outcome {
+            audit_on_behalf(ctx, &session, player_id, format!("name: {}", player.name));
+            if player.id == ctx.data_unchecked::<SessionIdentity>().id {
+                if let Some(change) = ctx.data_opt::<Arc<NameChange>>() {
+                    change.set(player.name.clone());
+                }
+            }
+        }
+        Ok(outcome)
     }
 
     async fn set_player_card(
         &self,
         ctx: &Context<'_>,
-        player_id: PlayerId,
+        room: String,
+        player_id: Option<PlayerId>,
         card: Option<i32>,
     ) -> Result<Option<Player>> {
         let card = card.map(|n| n as usize);
-        let session = ctx.data_unchecked::<Arc<crate::poker::PlaySession>>();
-        let outcome = {
-            let mut game_state = session.game_state.lock().unwrap();
+        let session = room_session(ctx, &room)?;
+        let player_id = acting_player(ctx, &session, player_id)?;
+        let viewer = Viewer::new(ctx, &session);
+        let outcome = session.update_and_emit(|game_state, events| {
+            let visible = viewer.can_see(game_state, player_id);
             if game_state.is_calling {
                 return Err(Error::new(
                     "Game is currently calling. Selections are locked.",
                 ));
             }
+            if let Some(card) = card {
+                if card >= session.deck(game_state).cards.len() {
+                    return Err(Error::new(format!("No such card: `{}`", card)));
+                }
+            }
             if let Some(player) = game_state.players.get_mut(&player_id) {
+                if !player.role.votes() {
+                    return Err(Error::new("Spectators don't get to vote."));
+                }
                 match player.selected_card.take() {
                     prev if prev == card => (),
                     _ => player.selected_card = card,
                 }
-                Ok(Some(Player::from(player.clone())))
+                events.push(crate::poker::GameEvent::PlayerVoted(
+                    player.id,
+                    player.selected_card.is_some(),
+                ));
+                Ok(Some(Player::new(&session, player, visible)))
             } else {
                 log::warn!("Tried to update card for unknown player: `{}`", player_id);
                 Ok(None)
             }
-        };
+        })??;
         session.notify_subscribers();
-        outcome
+        if let Some(player) = &outcome {
+            session.vote_changed();
+            audit_on_behalf(
+                ctx,
+                &session,
+                player_id,
+                format!("has card: {}", player.has_voted),
+            );
+        }
+        Ok(outcome)
     }
 
-    async fn remove_player(&self, ctx: &Context<'_>, player_id: PlayerId) -> Result<bool> {
-        let session = ctx.data_unchecked::<Arc<crate::poker::PlaySession>>();
-        {
-            let mut game_state = session.game_state.lock().unwrap();
-            game_state.players.remove(&player_id);
-        }
+    /// Takes a player out of the room, the caller by default.
+    async fn remove_player(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        player_id: Option<PlayerId>,
+    ) -> Result<bool> {
+        let session = room_session(ctx, &room)?;
+        let player_id = acting_player(ctx, &session, player_id)?;
+        let removed = session.update_and_emit(|game_state, events| {
+            let removed = game_state.players.remove(&player_id);
+            if removed.is_some() {
+                events.push(crate::poker::GameEvent::PlayerLeft(player_id));
+            }
+            removed
+        })?;
         session.notify_subscribers();
+        if removed.is_some() {
+            session.maybe_auto_call();
+        }
+        audit_on_behalf(
+            ctx,
+            &session,
+            player_id,
+            format!("removed: {}", removed.is_some()),
+        );
         Ok(true)
     }
 
-    async fn call(&self, ctx: &Context<'_>) -> Result<bool> {
-        let session = ctx.data_unchecked::<Arc<crate::poker::PlaySession>>();
-        {
-            let mut game_state = session.game_state.lock().unwrap();
-            game_state.is_calling = true;
+    /// Takes a player out of the room and lets them know. With `ban`, they
+    /// can't come back under the same identity, and with `ban_name` under
+    /// the same name either.
+    ///
+    /// The name banned is the player's current one, or `name` when they
+    /// aren't in the room.
+    // Each argument is a GraphQL argument.
+    #[allow(clippy::too_many_arguments)]
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn kick_player(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        player_id: PlayerId,
+        reason: Option<String>,
+        #[graphql(default)] ban: bool,
+        #[graphql(default)] ban_name: bool,
+        name: Option<String>,
+    ) -> Result<bool> {
+        let session = room_session(ctx, &room)?;
+        let removed = session.update_and_emit(|game_state, events| {
+            let banned_name = game_state
+                .players
+                .get(&player_id)
+                .map(|player| player.name.clone())
+                .or_else(|| name.as_deref().map(str::trim).map(String::from))
+                .filter(|name| !name.is_empty());
+            match &banned_name {
+                None if ban_name => {
+                    return Err(Error::new(format!(
+                        "Player `{}` isn't in the room, pass the `name` to ban.",
+                        player_id
+                    )));
+                }
+                // Everybody starts out with the default name.
+                Some(name) if ban_name && name.eq_ignore_ascii_case(DEFAULT_PLAYER_NAME) => {
+                    return Err(Error::new(format!(
+                        "`{}` is the name new players get, ban the player instead.",
+                        DEFAULT_PLAYER_NAME
+                    )));
+                }
+                _ => (),
+            }
+            let removed = game_state.players.remove(&player_id);
+            if ban || ban_name {
+                game_state.bans.push(crate::poker::Ban {
+                    id: BanId::new_v4(),
+                    player_id: Some(player_id).filter(|_| ban),
+                    name: banned_name.filter(|_| ban_name),
+                    reason: reason.clone(),
+                    banned_at: SystemTime::now(),
+                });
+            }
+            if removed.is_some() {
+                events.push(crate::poker::GameEvent::PlayerLeft(player_id));
+            }
+            events.push(crate::poker::GameEvent::Kicked(crate::poker::Kick {
+                player_id,
+                reason,
+                banned: ban || ban_name,
+            }));
+            Ok(removed)
+        })??;
+        session.notify_subscribers();
+        if removed.is_some() {
+            session.maybe_auto_call();
         }
+        guard::audit(
+            ctx,
+            Some(session.room_id),
+            Some(player_id.to_string()),
+            format!(
+                "ban: {}, ban name: {}, removed: {}",
+                ban,
+                ban_name,
+                removed.is_some()
+            ),
+        );
+        Ok(removed.is_some())
+    }
+
+    /// Lets a banned player back in.
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn lift_ban(&self, ctx: &Context<'_>, room: String, ban_id: BanId) -> Result<bool> {
+        let session = room_session(ctx, &room)?;
+        let lifted = session.update(|game_state| {
+            let prev_len = game_state.bans.len();
+            game_state.bans.retain(|ban| ban.id != ban_id);
+            prev_len != game_state.bans.len()
+        })?;
+        guard::audit(
+            ctx,
+            Some(session.room_id),
+            Some(ban_id.to_string()),
+            format!("lifted: {}", lifted),
+        );
+        Ok(lifted)
+    }
+
+    /// Switches the room to a different deck.
+    ///
+    /// Card selections are cleared and the round starts over, since the old
+    /// selections would point at the wrong cards.
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn set_deck(&self, ctx: &Context<'_>, room: String, deck: String) -> Result<Deck> {
+        let session = room_session(ctx, &room)?;
+        let deck = session.decks.get(&deck)?;
+        session.update_and_emit(|game_state, events| {
+            game_state.reset_round(&session.deck(game_state));
+            game_state.deck = Some(deck.name.clone());
+            events.push(crate::poker::GameEvent::Reset);
+            events.push(crate::poker::GameEvent::settings_changed(
+                &session, game_state,
+            ));
+        })?;
         session.notify_subscribers();
-        Ok(true)
+        guard::audit(
+            ctx,
+            Some(session.room_id),
+            None,
+            format!("deck: {}", deck.name),
+        );
+        Ok(Deck::from(&*deck))
     }
 
-    async fn resume(&self, ctx: &Context<'_>) -> Result<bool> {
-        let session = ctx.data_unchecked::<Arc<crate::poker::PlaySession>>();
-        {
-            let mut game_state = session.game_state.lock().unwrap();
-            game_state.is_calling = false;
+    /// Adds a story to the end of the queue.
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn add_story(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        title: String,
+        description: Option<String>,
+        link: Option<String>,
+    ) -> Result<Story> {
+        let session = room_session(ctx, &room)?;
+        let title = title.trim().to_string();
+        if title.is_empty() {
+            return Err(Error::new("Stories need a title."));
         }
+        let story = crate::poker::Story::new(title, description, link);
+        let outcome = Story::from(&story);
+        session.update_and_emit(|game_state, events| {
+            game_state.stories.push(story);
+            events.push(crate::poker::GameEvent::story_changed(game_state));
+        })?;
+        session.notify_subscribers();
+        guard::audit(
+            ctx,
+            Some(session.room_id),
+            Some(outcome.id.to_string()),
+            format!("title: {}", outcome.title),
+        );
+        Ok(outcome)
+    }
+
+    /// Moves a story to a new position in the queue, starting from 0.
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn move_story(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        story_id: StoryId,
+        position: i32,
+    ) -> Result<Vec<Story>> {
+        let session = room_session(ctx, &room)?;
+        let stories = session.update_and_emit(|game_state, events| {
+            let idx = game_state
+                .stories
+                .iter()
+                .position(|story| story.id == story_id)
+                .ok_or_else(|| Error::new(format!("Unknown story: `{}`", story_id)))?;
+            let story = game_state.stories.remove(idx);
+            let position = (position.max(0) as usize).min(game_state.stories.len());
+            game_state.stories.insert(position, story);
+            events.push(crate::poker::GameEvent::story_changed(game_state));
+            Ok::<_, Error>(game_state.stories.iter().map(Story::from).collect())
+        })??;
+        session.notify_subscribers();
+        guard::audit(
+            ctx,
+            Some(session.room_id),
+            Some(story_id.to_string()),
+            format!("position: {}", position),
+        );
+        Ok(stories)
+    }
+
+    /// Skips a story, the current one by default. When the current story is
+    /// skipped, the next pending story takes its place.
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn skip_story(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        story_id: Option<StoryId>,
+    ) -> Result<Story> {
+        let session = room_session(ctx, &room)?;
+        let story = session.update_and_emit(|game_state, events| {
+            let story_id = story_id
+                .or(game_state.current_story)
+                .ok_or_else(|| Error::new("There is no current story to skip."))?;
+            let reset = game_state.current_story == Some(story_id);
+            let story = game_state
+                .skip(story_id, &session.deck(game_state))
+                .map(Story::from)
+                .ok_or_else(|| Error::new(format!("Unknown story: `{}`", story_id)))?;
+            if reset {
+                events.push(crate::poker::GameEvent::Reset);
+            }
+            events.push(crate::poker::GameEvent::story_changed(game_state));
+            Ok::<_, Error>(story)
+        })??;
         session.notify_subscribers();
+        guard::audit(ctx, Some(session.room_id), Some(story.id.to_string()), "");
+        Ok(story)
+    }
+
+    /// Records the agreed estimate against the current story, and against the
+    /// round in progress once it is reset.
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn accept_estimate(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        card: i32,
+    ) -> Result<Option<Story>> {
+        let session = room_session(ctx, &room)?;
+        let (story, label) = session.update_and_emit(|game_state, events| {
+            let deck = session.deck(game_state);
+            let card = Some(card)
+                .filter(|card| *card >= 0)
+                .and_then(|idx| deck.cards.get(idx as usize))
+                .cloned()
+                .ok_or_else(|| Error::new(format!("No such card: `{}`", card)))?;
+            let label = card.label.clone();
+            let story = game_state.accept_estimate(card).map(Story::from);
+            events.push(crate::poker::GameEvent::story_changed(game_state));
+            Ok::<_, Error>((story, label))
+        })??;
+        session.notify_subscribers();
+        guard::audit(
+            ctx,
+            Some(session.room_id),
+            story.as_ref().map(|story| story.id.to_string()),
+            format!("estimate: {}", label),
+        );
+        Ok(story)
+    }
+
+    /// Finishes the current story and starts a fresh round for the next
+    /// pending one. Returns the new current story.
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn next_story(&self, ctx: &Context<'_>, room: String) -> Result<Option<Story>> {
+        let session = room_session(ctx, &room)?;
+        let story = session.update_and_emit(|game_state, events| {
+            let story = game_state
+                .advance(&session.deck(game_state))
+                .map(Story::from);
+            events.push(crate::poker::GameEvent::Reset);
+            events.push(crate::poker::GameEvent::story_changed(game_state));
+            story
+        })?;
+        session.notify_subscribers();
+        guard::audit(
+            ctx,
+            Some(session.room_id),
+            story.as_ref().map(|story| story.id.to_string()),
+            "",
+        );
+        Ok(story)
+    }
+
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn call(&self, ctx: &Context<'_>, room: String) -> Result<bool> {
+        let session = room_session(ctx, &room)?;
+        session.call()?;
+        guard::audit(ctx, Some(session.room_id), None, "");
         Ok(true)
     }
 
-    async fn reset(&self, ctx: &Context<'_>) -> Result<bool> {
-        let session = ctx.data_unchecked::<Arc<crate::poker::PlaySession>>();
-        {
-            let mut game_state = session.game_state.lock().unwrap();
-            for mut player in game_state.players.values_mut() {
-                player.selected_card = None;
+    /// Changes how the room decides it has reached consensus.
+    ///
+    /// `percent` is required for, and only used by, the supermajority rule.
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn set_consensus_rule(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        kind: ConsensusRuleKind,
+        percent: Option<i32>,
+    ) -> Result<ConsensusRule> {
+        let session = room_session(ctx, &room)?;
+        let rule = match (kind, percent) {
+            (ConsensusRuleKind::Unanimous, _) => crate::poker::ConsensusRule::Unanimous,
+            (ConsensusRuleKind::Adjacent, _) => crate::poker::ConsensusRule::Adjacent,
+            (ConsensusRuleKind::Supermajority, Some(percent)) if (51..=100).contains(&percent) => {
+                crate::poker::ConsensusRule::Supermajority {
+                    percent: percent as u8,
+                }
+            }
+            (ConsensusRuleKind::Supermajority, _) => {
+                return Err(Error::new(
+                    "A supermajority needs a `percent` between 51 and 100.",
+                ))
             }
-            game_state.is_calling = false;
+        };
+        session.update_and_emit(|game_state, events| {
+            game_state.settings.consensus_rule = rule;
+            events.push(crate::poker::GameEvent::settings_changed(
+                &session, game_state,
+            ));
+        })?;
+        session.notify_subscribers();
+        guard::audit(
+            ctx,
+            Some(session.room_id),
+            None,
+            format!("rule: {:?}", rule),
+        );
+        Ok(rule.into())
+    }
+
+    /// Starts a countdown for the round in progress, replacing any running
+    /// one. Subscribers hear about it as it starts, as it runs low and as it
+    /// runs out.
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn start_timer(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        seconds: i32,
+        #[graphql(default_with = "TimerExpiry::Call")] on_expiry: TimerExpiry,
+    ) -> Result<RoundTimer> {
+        if !(1..=MAX_TIMER_SECS).contains(&seconds) {
+            return Err(Error::new(format!(
+                "Timers need to be between 1 and {} seconds long.",
+                MAX_TIMER_SECS
+            )));
+        }
+        let session = room_session(ctx, &room)?;
+        if session.read(|game_state| game_state.is_calling)? {
+            return Err(Error::new("The round has already been called."));
+        }
+        let timer = session.start_timer(Duration::from_secs(seconds as u64), on_expiry.into())?;
+        guard::audit(
+            ctx,
+            Some(session.room_id),
+            None,
+            format!("seconds: {}, on expiry: {:?}", seconds, on_expiry),
+        );
+        Ok(RoundTimer::from(&timer))
+    }
+
+    /// Stops the countdown for the round. Returns whether one was running.
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn cancel_timer(&self, ctx: &Context<'_>, room: String) -> Result<bool> {
+        let session = room_session(ctx, &room)?;
+        let cancelled = session.cancel_timer()?;
+        guard::audit(
+            ctx,
+            Some(session.room_id),
+            None,
+            format!("cancelled: {}", cancelled),
+        );
+        Ok(cancelled)
+    }
+
+    /// Sets the password players need to join, or clears it when `password`
+    /// is null. Players already in the room stay.
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn set_room_password(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        password: Option<String>,
+    ) -> Result<bool> {
+        let session = room_session(ctx, &room)?;
+        let password_hash = match password.as_deref().map(str::trim) {
+            Some("") => return Err(Error::new("Passwords can't be blank.")),
+            Some(password) => Some(
+                crate::access::hash_password(password).map_err(|e| Error::new(e.to_string()))?,
+            ),
+            None => None,
+        };
+        let protected = password_hash.is_some();
+        session.update_and_emit(|game_state, events| {
+            game_state.settings.password_hash = password_hash;
+            events.push(crate::poker::GameEvent::settings_changed(
+                &session, game_state,
+            ));
+        })?;
+        session.notify_subscribers();
+        guard::audit(
+            ctx,
+            Some(session.room_id),
+            None,
+            format!("protected: {}", protected),
+        );
+        Ok(protected)
+    }
+
+    /// Mints an invite token for a password protected room.
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn create_invite(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        #[graphql(default = 86400)] expires_in_secs: i32,
+    ) -> Result<Invite> {
+        if !(1..=MAX_INVITE_SECS).contains(&expires_in_secs) {
+            return Err(Error::new(format!(
+                "Invites need to expire within 1 to {} seconds.",
+                MAX_INVITE_SECS
+            )));
         }
+        let session = room_session(ctx, &room)?;
+        let expires_at = SystemTime::now() + Duration::from_secs(expires_in_secs as u64);
+        guard::audit(
+            ctx,
+            Some(session.room_id),
+            None,
+            format!("expires in: {}s", expires_in_secs),
+        );
+        Ok(Invite {
+            token: crate::access::mint_invite(&session.admin_key, session.room_id, expires_at),
+            expires_at: expires_at.into(),
+        })
+    }
+
+    /// Turns anonymous mode on or off. While on, only admins get to see who
+    /// picked which card.
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn set_anonymous(&self, ctx: &Context<'_>, room: String, enabled: bool) -> Result<bool> {
+        let session = room_session(ctx, &room)?;
+        session.update_and_emit(|game_state, events| {
+            game_state.settings.anonymous = enabled;
+            events.push(crate::poker::GameEvent::settings_changed(
+                &session, game_state,
+            ));
+        })?;
+        session.notify_subscribers();
+        guard::audit(
+            ctx,
+            Some(session.room_id),
+            None,
+            format!("enabled: {}", enabled),
+        );
+        Ok(enabled)
+    }
+
+    /// Turns automatic calling on or off. `delay_ms` is capped at a minute.
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn set_auto_call(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+        enabled: bool,
+        #[graphql(default)] delay_ms: i32,
+    ) -> Result<AutoCall> {
+        let session = room_session(ctx, &room)?;
+        let auto_call = if enabled {
+            Some(crate::poker::AutoCall {
+                delay_ms: delay_ms.clamp(0, MAX_AUTO_CALL_DELAY_MS) as u64,
+            })
+        } else {
+            None
+        };
+        session.update_and_emit(|game_state, events| {
+            game_state.settings.auto_call = auto_call;
+            events.push(crate::poker::GameEvent::settings_changed(
+                &session, game_state,
+            ));
+        })?;
+        session.notify_subscribers();
+        session.maybe_auto_call();
+        guard::audit(
+            ctx,
+            Some(session.room_id),
+            None,
+            match auto_call {
+                Some(auto_call) => format!("enabled: true, delay: {}ms", auto_call.delay_ms),
+                None => String::from("enabled: false"),
+            },
+        );
+        Ok(auto_call.into())
+    }
+
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn resume(&self, ctx: &Context<'_>, room: String) -> Result<bool> {
+        let session = room_session(ctx, &room)?;
+        let resumed = session.update_and_emit(|game_state, events| {
+            let resumed = std::mem::replace(&mut game_state.is_calling, false);
+            if resumed {
+                events.push(crate::poker::GameEvent::Resumed);
+            }
+            resumed
+        })?;
         session.notify_subscribers();
+        guard::audit(
+            ctx,
+            Some(session.room_id),
+            None,
+            format!("resumed: {}", resumed),
+        );
+        Ok(true)
+    }
+
+    #[graphql(guard = "AdminGuard::new(&room)")]
+    async fn reset(&self, ctx: &Context<'_>, room: String) -> Result<bool> {
+        let session = room_session(ctx, &room)?;
+        session.update_and_emit(|game_state, events| {
+            game_state.reset_round(&session.deck(game_state));
+            events.push(crate::poker::GameEvent::Reset);
+        })?;
+        session.notify_subscribers();
+        guard::audit(ctx, Some(session.room_id), None, "");
         Ok(true)
     }
 }
 
 pub struct Subscription;
 
-// FIXME: Seems like we could update the subscription to yield a union of
-//  players (individually) and separate messages for game state events?
-//  It might not be worth it since the overall size is going to be small...
-
 #[Subscription]
 impl Subscription {
-    async fn game_state(&self, ctx: &Context<'_>) -> impl Stream<Item = GameState> {
-        let session = ctx.data_unchecked::<Arc<crate::poker::PlaySession>>();
-        let rx = BroadcastStream::new(session.game_state_notifier.subscribe());
+    /// Also marks the caller as present in the room until the subscription
+    /// ends. Ends once the caller is kicked.
+    #[graphql(guard = "MemberGuard::new(&room)")]
+    async fn game_state(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+    ) -> Result<impl Stream<Item = Result<GameState>>> {
+        let session = room_session(ctx, &room)?;
+        let identity = ctx
+            .data_opt::<SessionIdentity>()
+            .map(|identity| identity.id);
+        let presence = identity.map(|player_id| session.connected(player_id));
+        let changes = BroadcastStream::new(session.game_state_notifier.subscribe())
+            .map(|_| true)
+            .merge(kicks(&session, identity).map(|_| false))
+            .take_while(|changed| *changed);
         // Who knows when the next game state change will happen, so seed the
         // stream with one message to kick things off.
-        let init = stream::iter(vec![GameState]);
+        let viewer = Viewer::new(ctx, &session);
+        let init = stream::iter(vec![GameState::load(&session, viewer)]);
         // Additional game states will flow over the socket with each time a
         // mutation happens (ie, when `notify_subscribers()` is called).
-        init.merge(rx.map(|_| GameState))
+        Ok(init.merge(changes.map(move |_| {
+            // Dropped along with the stream when the socket closes.
+            let _presence = &presence;
+            GameState::load(&session, viewer)
+        })))
+    }
+
+    /// Yields each change to the game state as it happens, starting from the
+    /// next one. Load the `gameState` first, then apply these on top.
+    ///
+    /// Sequence numbers go up by one per room, so a skipped number means an
+    /// event was missed and the state should be loaded again. They start over
+    /// when the server restarts.
+    ///
+    /// Also marks the caller as present in the room until the subscription
+    /// ends. Ends after telling the caller they were kicked.
+    #[graphql(guard = "MemberGuard::new(&room)")]
+    async fn game_events(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+    ) -> Result<impl Stream<Item = GameEvent>> {
+        let session = room_session(ctx, &room)?;
+        let identity = ctx
+            .data_opt::<SessionIdentity>()
+            .map(|identity| identity.id);
+        let presence = identity.map(|player_id| session.connected(player_id));
+        let viewer = Viewer::new(ctx, &session);
+        let rx = BroadcastStream::new(session.event_notifier.subscribe());
+        // Ends straight after the kick, without waiting on the next event.
+        let rx = futures_util::stream::unfold((rx, false), move |(mut rx, kicked)| async move {
+            if kicked {
+                return None;
+            }
+            let msg = rx.next().await?;
+            let kicked = matches!(&msg, Ok((_, event)) if is_kick_of(event, identity));
+            Some((msg, (rx, kicked)))
+        });
+        let events = rx.filter_map(move |msg| {
+            let _presence = &presence;
+            match msg {
+                Ok((sequence, event)) => Some(GameEvent::new(&session, viewer, sequence, event)),
+                Err(e) => {
+                    // The client will notice the gap in the sequence numbers.
+                    log::warn!("{}", e);
+                    None
+                }
+            }
+        });
+        Ok(events)
+    }
+
+    /// Yields the results each time a call ends in consensus.
+    #[graphql(guard = "MemberGuard::new(&room)")]
+    async fn consensus_reached(
+        &self,
+        ctx: &Context<'_>,
+        room: String,
+    ) -> Result<impl Stream<Item = Results>> {
+        let session = room_session(ctx, &room)?;
+        let rx = BroadcastStream::new(session.consensus_notifier.subscribe());
+        Ok(rx.filter_map(|msg| msg.ok().map(|(deck, results)| Results::new(&deck, results))))
+    }
+}
+
+/// Whether the event takes the given player out of the room.
+fn is_kick_of(event: &crate::poker::GameEvent, player_id: Option<PlayerId>) -> bool {
+    matches!(event, crate::poker::GameEvent::Kicked(kick) if Some(kick.player_id) == player_id)
+}
+
+/// Yields each time the player is kicked from the room.
+fn kicks(session: &PlaySession, player_id: Option<PlayerId>) -> impl Stream<Item = ()> {
+    BroadcastStream::new(session.event_notifier.subscribe()).filter_map(move |msg| match msg {
+        Ok((_, event)) if is_kick_of(&event, player_id) => Some(()),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deck::Decks;
+    use crate::storage::MemoryStorage;
+    use async_graphql::{Request, Response};
+
+    struct Fixture {
+        schema: PokerSchema,
+        registry: Arc<RoomRegistry>,
+        room: Arc<crate::rooms::Room>,
+    }
+
+    /// A server with a single password protected room.
+    fn fixture() -> Fixture {
+        let decks = Decks::default();
+        let default_deck = decks.get("fib").unwrap();
+        let registry = Arc::new(RoomRegistry::new(
+            Arc::new(decks),
+            default_deck,
+            Duration::from_secs(60),
+            Arc::new(MemoryStorage::default()),
+            2,
+        ));
+        let room = registry
+            .create(Some("locked".to_string()), None, None, None)
+            .unwrap();
+        let hash = crate::access::hash_password("hunter2").unwrap();
+        room.session
+            .update(|game_state| game_state.settings.password_hash = Some(hash))
+            .unwrap();
+        let schema = Schema::build(Query, Mutation, Subscription)
+            .data(registry.clone())
+            .data(Arc::new(AdminCredentials::default()))
+            .finish();
+        Fixture {
+            schema,
+            registry,
+            room,
+        }
+    }
+
+    impl Fixture {
+        /// A request from `player`, holding an admin grant for the room when
+        /// `admin` is set.
+        fn request(&self, player: PlayerId, admin: bool, query: &str) -> Request {
+            let grants = AdminGrants::default();
+            if admin {
+                grants.grant(self.room.id, &self.room.session.admin_key);
+            }
+            Request::new(query)
+                .data(SessionIdentity {
+                    name: "Ada".to_string(),
+                    id: player,
+                })
+                .data(Arc::new(grants))
+        }
+
+        async fn execute(&self, player: PlayerId, admin: bool, query: &str) -> Response {
+            self.schema
+                .execute(self.request(player, admin, query))
+                .await
+        }
+
+        fn subscribe(
+            &self,
+            player: PlayerId,
+            admin: bool,
+            query: &str,
+        ) -> impl Stream<Item = Response> + Unpin {
+            self.schema
+                .execute_stream(self.request(player, admin, query))
+        }
+
+        async fn register(&self, player: PlayerId) {
+            self.data(
+                player,
+                false,
+                r#"mutation { register(room: "locked", password: "hunter2") }"#,
+            )
+            .await;
+        }
+
+        /// Runs a query or mutation that is expected to succeed.
+        async fn data(&self, player: PlayerId, admin: bool, query: &str) -> serde_json::Value {
+            let response = self.execute(player, admin, query).await;
+            assert!(
+                response.errors.is_empty(),
+                "{}: {:?}",
+                query,
+                response.errors
+            );
+            response.data.into_json().unwrap()
+        }
+
+        /// The card `target` has selected, as the game state shows it to
+        /// `viewer`.
+        async fn selected_card(
+            &self,
+            viewer: PlayerId,
+            admin: bool,
+            target: PlayerId,
+        ) -> serde_json::Value {
+            let data = self.data(viewer, admin, PLAYER_CARDS).await;
+            card_of(&data, target)
+        }
+    }
+
+    const PLAYER_CARDS: &str = r#"{ gameState(room: "locked") { players { id selectedCard } } }"#;
+    const WATCH_PLAYER_CARDS: &str =
+        r#"subscription { gameState(room: "locked") { players { id selectedCard } } }"#;
+
+    fn card_of(data: &serde_json::Value, player: PlayerId) -> serde_json::Value {
+        data.pointer("/gameState/players")
+            .and_then(|players| players.as_array())
+            .and_then(|players| {
+                players
+                    .iter()
+                    .find(|p| p["id"] == serde_json::json!(player.to_string()))
+            })
+            .map(|p| p["selectedCard"].clone())
+            .expect("player is in the game state")
+    }
+
+    /// The only card played in the latest round, as shown to `viewer`.
+    async fn last_vote(fixture: &Fixture, viewer: PlayerId, admin: bool) -> serde_json::Value {
+        let data = fixture
+            .data(
+                viewer,
+                admin,
+                r#"{ rounds(room: "locked") { rounds { votes { playerId name card { label } } } } }"#,
+            )
+            .await;
+        let votes = data.pointer("/rounds/rounds/0/votes").unwrap();
+        let mut played = votes
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|v| !v["card"].is_null());
+        let vote = played.next().expect("a card was played").clone();
+        assert!(played.next().is_none());
+        vote
+    }
+
+    fn error_code(response: &Response) -> Option<String> {
+        let errors = serde_json::to_value(&response.errors).ok()?;
+        let code = errors.pointer("/0/extensions/code")?.as_str()?;
+        Some(code.to_string())
+    }
+
+    const GAME_STATE: &str = r#"{ gameState(room: "locked") { isCalling } }"#;
+
+    #[actix_rt::test]
+    async fn protected_rooms_are_hidden_from_strangers() {
+        let fixture = fixture();
+        let stranger = PlayerId::new_v4();
+        for query in [
+            GAME_STATE,
+            r#"{ cards(room: "locked") { label } }"#,
+            r#"{ rounds(room: "locked") { total } }"#,
+        ] {
+            let response = fixture.execute(stranger, false, query).await;
+            assert_eq!(
+                error_code(&response).as_deref(),
+                Some("PASSWORD_REQUIRED"),
+                "{}",
+                query
+            );
+        }
+        fixture
+            .data(
+                stranger,
+                false,
+                r#"{ room(room: "locked") { passwordProtected } }"#,
+            )
+            .await;
+    }
+
+    #[actix_rt::test]
+    async fn protected_rooms_are_shown_to_players_and_admins() {
+        let fixture = fixture();
+        let player = PlayerId::new_v4();
+        fixture.register(player).await;
+        fixture.data(player, false, GAME_STATE).await;
+        fixture.data(PlayerId::new_v4(), true, GAME_STATE).await;
+    }
+
+    #[actix_rt::test]
+    async fn admin_mutations_are_audited_once_done() {
+        let fixture = fixture();
+        let admin = PlayerId::new_v4();
+        let response = fixture
+            .execute(
+                admin,
+                true,
+                r#"mutation { setConsensusRule(room: "locked", kind: SUPERMAJORITY) { kind } }"#,
+            )
+            .await;
+        assert!(!response.errors.is_empty());
+        assert!(fixture.registry.audit_log(10).unwrap().is_empty());
+
+        let player = PlayerId::new_v4();
+        let query = format!(
+            r#"mutation {{ kickPlayer(room: "locked", playerId: "{}", ban: true, name: "Bob") }}"#,
+            player
+        );
+        fixture.data(admin, true, &query).await;
+        let log = fixture.registry.audit_log(10).unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].action, "kickPlayer");
+        assert_eq!(log[0].player_id, admin);
+        assert_eq!(log[0].target, Some(player.to_string()));
+        assert_eq!(
+            log[0].details.as_deref(),
+            Some("ban: true, ban name: false, removed: false")
+        );
+    }
+
+    #[actix_rt::test]
+    async fn admin_grants_lapse_when_the_key_changes() {
+        let fixture = fixture();
+        let room = fixture.room.id;
+        let current = crate::access::key_fingerprint(&fixture.room.session.admin_key, room);
+        let stale = crate::access::key_fingerprint("old admin key", room);
+
+        let grants = AdminGrants::new([(room, current)].into(), &fixture.registry, false, None);
+        assert!(grants.contains(&fixture.room));
+        assert!(grants.take_changes().is_none());
+
+        let grants = AdminGrants::new([(room, stale)].into(), &fixture.registry, false, None);
+        assert!(!grants.contains(&fixture.room));
+        assert_eq!(grants.take_changes(), Some(Default::default()));
+    }
+
+    #[actix_rt::test]
+    async fn facilitators_only_run_the_rooms_they_are_given() {
+        let fixture = fixture();
+        let other = fixture
+            .registry
+            .create(Some("other".to_string()), None, None, None)
+            .unwrap();
+        let hash = crate::access::hash_password("secret").unwrap();
+        let credentials = AdminCredentials::parse(
+            &format!(
+                r#"
+                [[credential]]
+                name = "bob"
+                role = "facilitator"
+                rooms = ["locked"]
+                password_hash = "{hash}"
+
+                [[credential]]
+                name = "alice"
+                role = "server-operator"
+                password_hash = "{hash}"
+                "#
+            ),
+            false,
+        )
+        .unwrap();
+        let grants_for = |name: &str| {
+            let credential = credentials.get(name).cloned();
+            AdminGrants::new(Default::default(), &fixture.registry, false, credential)
+        };
+
+        let facilitator = grants_for("bob");
+        assert!(facilitator.contains(&fixture.room));
+        assert!(!facilitator.contains(&other));
+
+        let operator = grants_for("alice");
+        assert!(operator.contains(&fixture.room));
+        assert!(operator.contains(&other));
+    }
+
+    #[actix_rt::test]
+    async fn rounds_called_then_resumed_are_still_recorded() {
+        let fixture = fixture();
+        let admin = PlayerId::new_v4();
+        for query in [
+            r#"mutation { call(room: "locked") }"#,
+            r#"mutation { resume(room: "locked") }"#,
+            r#"mutation { reset(room: "locked") }"#,
+        ] {
+            fixture.data(admin, true, query).await;
+        }
+        let data = fixture
+            .data(admin, true, r#"{ rounds(room: "locked") { total } }"#)
+            .await;
+        assert_eq!(data.pointer("/rounds/total"), Some(&1.into()));
+    }
+
+    #[actix_rt::test]
+    async fn negative_estimates_are_refused() {
+        let fixture = fixture();
+        let response = fixture
+            .execute(
+                PlayerId::new_v4(),
+                true,
+                r#"mutation { acceptEstimate(room: "locked", card: -3) { id } }"#,
+            )
+            .await;
+        assert!(!response.errors.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn the_default_name_cannot_be_banned() {
+        let fixture = fixture();
+        let query = format!(
+            r#"mutation {{ kickPlayer(room: "locked", playerId: "{}", banName: true, name: "guest") }}"#,
+            PlayerId::new_v4()
+        );
+        let response = fixture.execute(PlayerId::new_v4(), true, &query).await;
+        assert!(!response.errors.is_empty());
+        assert!(fixture
+            .room
+            .session
+            .read(|game_state| game_state.bans.is_empty())
+            .unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn kicking_a_player_ends_their_subscriptions() {
+        let fixture = fixture();
+        let player = PlayerId::new_v4();
+        fixture.register(player).await;
+
+        let mut states = fixture.subscribe(
+            player,
+            false,
+            r#"subscription { gameState(room: "locked") { isCalling } }"#,
+        );
+        let mut events = fixture.subscribe(
+            player,
+            false,
+            r#"subscription { gameEvents(room: "locked") { __typename } }"#,
+        );
+        let first = states.next().await.unwrap();
+        assert!(first.errors.is_empty(), "{:?}", first.errors);
+        // Subscriptions start once polled, and there are no events yet.
+        let pending = tokio::time::timeout(Duration::from_millis(10), events.next()).await;
+        assert!(pending.is_err());
+
+        let query = format!(
+            r#"mutation {{ kickPlayer(room: "locked", playerId: "{}", ban: true) }}"#,
+            player
+        );
+        fixture.data(PlayerId::new_v4(), true, &query).await;
+
+        let timeout = Duration::from_secs(1);
+        let kicked = tokio::time::timeout(timeout, events.collect::<Vec<_>>())
+            .await
+            .expect("the event stream should end");
+        // Told they left, then that they were kicked.
+        assert_eq!(kicked.len(), 2);
+        tokio::time::timeout(timeout, states.collect::<Vec<_>>())
+            .await
+            .expect("the state stream should end");
+    }
+
+    #[actix_rt::test]
+    async fn cards_are_hidden_until_the_call() {
+        let fixture = fixture();
+        let (alice, bob, admin) = (PlayerId::new_v4(), PlayerId::new_v4(), PlayerId::new_v4());
+        fixture.register(alice).await;
+        fixture.register(bob).await;
+        fixture
+            .data(
+                alice,
+                false,
+                r#"mutation { setPlayerCard(room: "locked", card: 2) { id } }"#,
+            )
+            .await;
+
+        let mut states = fixture.subscribe(bob, false, WATCH_PLAYER_CARDS);
+        let first = states.next().await.unwrap().data.into_json().unwrap();
+        assert_eq!(card_of(&first, alice), serde_json::Value::Null);
+        assert_eq!(
+            fixture.selected_card(bob, false, alice).await,
+            serde_json::Value::Null
+        );
+        assert_eq!(
+            fixture.selected_card(alice, false, alice).await,
+            serde_json::json!(2)
+        );
+
+        fixture
+            .data(admin, true, r#"mutation { call(room: "locked") }"#)
+            .await;
+        let called = states.next().await.unwrap().data.into_json().unwrap();
+        assert_eq!(card_of(&called, alice), serde_json::json!(2));
+        assert_eq!(
+            fixture.selected_card(bob, false, alice).await,
+            serde_json::json!(2)
+        );
+
+        fixture
+            .data(admin, true, r#"mutation { reset(room: "locked") }"#)
+            .await;
+        let vote = last_vote(&fixture, bob, false).await;
+        assert_eq!(vote["playerId"], serde_json::json!(alice.to_string()));
+        assert_eq!(vote["name"], "Ada");
+        assert_eq!(vote["card"]["label"], "2");
+    }
+
+    #[actix_rt::test]
+    async fn anonymous_cards_are_only_shown_to_admins() {
+        let fixture = fixture();
+        let (alice, bob, admin) = (PlayerId::new_v4(), PlayerId::new_v4(), PlayerId::new_v4());
+        fixture.register(alice).await;
+        fixture.register(bob).await;
+        fixture.register(admin).await;
+        fixture
+            .data(
+                admin,
+                true,
+                r#"mutation { setAnonymous(room: "locked", enabled: true) }"#,
+            )
+            .await;
+        fixture
+            .data(
+                alice,
+                false,
+                r#"mutation { setPlayerCard(room: "locked", card: 2) { id } }"#,
+            )
+            .await;
+        fixture
+            .data(admin, true, r#"mutation { call(room: "locked") }"#)
+            .await;
+
+        assert_eq!(
+            fixture.selected_card(bob, false, alice).await,
+            serde_json::Value::Null
+        );
+        assert_eq!(
+            fixture.selected_card(alice, false, alice).await,
+            serde_json::json!(2)
+        );
+        assert_eq!(
+            fixture.selected_card(admin, true, alice).await,
+            serde_json::json!(2)
+        );
+
+        let state = fixture
+            .subscribe(bob, false, WATCH_PLAYER_CARDS)
+            .next()
+            .await
+            .unwrap();
+        let state = state.data.into_json().unwrap();
+        assert_eq!(card_of(&state, alice), serde_json::Value::Null);
+        let state = fixture
+            .subscribe(admin, true, WATCH_PLAYER_CARDS)
+            .next()
+            .await
+            .unwrap();
+        let state = state.data.into_json().unwrap();
+        assert_eq!(card_of(&state, alice), serde_json::json!(2));
+
+        fixture
+            .data(admin, true, r#"mutation { reset(room: "locked") }"#)
+            .await;
+        // Leaving anonymous mode doesn't unmask rounds played in it.
+        fixture
+            .data(
+                admin,
+                true,
+                r#"mutation { setAnonymous(room: "locked", enabled: false) }"#,
+            )
+            .await;
+        let vote = last_vote(&fixture, bob, false).await;
+        assert_eq!(vote["playerId"], serde_json::Value::Null);
+        assert_eq!(vote["name"], serde_json::Value::Null);
+        assert_eq!(vote["card"]["label"], "2");
+        let vote = last_vote(&fixture, admin, true).await;
+        assert_eq!(vote["playerId"], serde_json::json!(alice.to_string()));
+    }
+
+    #[actix_rt::test]
+    async fn rooms_are_capped_until_one_is_deleted() {
+        let fixture = fixture();
+        let anyone = PlayerId::new_v4();
+        let create = |slug: &str| {
+            format!(
+                r#"mutation {{ createRoom(slug: "{}") {{ adminKey }} }}"#,
+                slug
+            )
+        };
+        fixture.data(anyone, false, &create("spare")).await;
+        let response = fixture.execute(anyone, false, &create("extra")).await;
+        assert!(!response.errors.is_empty());
+
+        let delete = r#"mutation { deleteRoom(room: "locked") }"#;
+        let response = fixture.execute(anyone, false, delete).await;
+        assert_eq!(error_code(&response).as_deref(), Some("FORBIDDEN"));
+        let data = fixture.data(anyone, true, delete).await;
+        assert_eq!(data["deleteRoom"], true);
+        assert!(fixture.registry.get("locked").is_none());
+        assert!(fixture.room.session.read(|_| ()).is_err());
+
+        fixture.data(anyone, false, &create("extra")).await;
     }
 }
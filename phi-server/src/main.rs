@@ -1,6 +1,5 @@
-use crate::poker::DeckType;
-use actix_session::CookieSession;
 use actix_web::cookie::SameSite;
+use actix_web::dev::Service;
 use actix_web::middleware::Logger;
 use actix_web::{web, App, HttpServer};
 use async_graphql::Schema;
@@ -9,9 +8,17 @@ use std::time::Duration;
 use structopt::StructOpt;
 use uuid::Uuid;
 
+mod access;
 mod cli;
+mod cookie_key;
+mod credentials;
+mod deck;
 mod gql;
+mod oidc;
 mod poker;
+mod rooms;
+mod stats;
+mod storage;
 
 // FIXME: need to rewrite BOTH spa impls so we can call `get_session_identity`
 //  Probably refactor to follow the handler flow in `baked` and change how we
@@ -96,57 +103,158 @@ async fn main() -> std::io::Result<()> {
 
     let opts: cli::Opt = cli::Opt::from_args();
 
-    let admin_key = opts.admin_key.unwrap_or_else(|| Uuid::new_v4().to_string());
+    if opts.hash_password {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password)?;
+        let hash = access::hash_password(password.trim_end_matches(&['\r', '\n'][..]))
+            .map_err(|e| into_io_error(e.to_string()))?;
+        println!("{}", hash);
+        return Ok(());
+    }
 
-    log::info!("Admin Key: {}", &admin_key);
     log::info!("Disconnect timeout secs: {}", opts.disconnect_timeout_secs);
     log::info!("Server listening on {}", opts.http_addr);
 
-    let play_session = Arc::new(poker::PlaySession::new(
-        admin_key,
-        opts.deck_type,
+    let storage: Arc<dyn storage::Storage> = if let Some(state_file) = &opts.state_file {
+        log::info!("State file: {:?}", state_file);
+        let storage =
+            Arc::new(storage::file::FileStorage::open(state_file.clone()).map_err(into_io_error)?);
+        tokio::spawn(
+            storage
+                .clone()
+                .run_writer(Duration::from_millis(opts.state_save_delay_ms)),
+        );
+        storage
+    } else if let Some(database) = &opts.database {
+        log::info!("Database: {:?}", database);
+        Arc::new(storage::sqlite::SqliteStorage::open(database).map_err(into_io_error)?)
+    } else {
+        Arc::new(storage::MemoryStorage::default())
+    };
+
+    let decks = deck::Decks::load(opts.deck_file.as_deref()).map_err(into_io_error)?;
+    let default_deck = decks.get(&opts.deck_type).map_err(into_io_error)?;
+    log::info!("Deck: {} {:?}", default_deck.name, default_deck.labels());
+
+    let registry = Arc::new(rooms::RoomRegistry::new(
+        Arc::new(decks),
+        default_deck,
         Duration::from_secs(opts.disconnect_timeout_secs),
+        storage.clone(),
+        opts.max_rooms,
     ));
-    let poker_data = web::Data::new(play_session.clone());
+    registry
+        .restore(opts.admin_key.as_ref())
+        .map_err(into_io_error)?;
+
+    let default_room = match registry.get(rooms::DEFAULT_ROOM_SLUG) {
+        Some(room) => room,
+        None => {
+            let admin_key = opts
+                .admin_key
+                .clone()
+                .unwrap_or_else(|| Uuid::new_v4().to_string());
+            registry
+                .create(
+                    Some(rooms::DEFAULT_ROOM_SLUG.to_string()),
+                    None,
+                    Some(admin_key),
+                    None,
+                )
+                .map_err(into_io_error)?
+        }
+    };
+    let credentials = Arc::new(
+        credentials::AdminCredentials::load(opts.admin_credentials_file.as_deref())
+            .map_err(into_io_error)?,
+    );
+    // Anyone reading the logs would otherwise get to be admin.
+    if credentials.is_empty() {
+        log::info!(
+            "Admin Key ({} room): {}",
+            rooms::DEFAULT_ROOM_SLUG,
+            &default_room.session.admin_key
+        );
+    }
+
+    let registry_data = web::Data::new(registry.clone());
 
     let schema = Schema::build(
         gql::model::Query,
         gql::model::Mutation,
         gql::model::Subscription,
     )
-    .data(play_session.clone())
+    .data(registry.clone())
+    .data(credentials.clone())
     .finish();
 
     let schema_data = web::Data::new(schema);
+    let credentials_data = web::Data::new(credentials);
+
+    let oidc_data = match oidc::OidcConfig::from_opts(&opts) {
+        Some(config) => {
+            log::info!("OpenID Connect issuer: {}", config.issuer);
+            let provider = oidc::Provider::discover(config)
+                .await
+                .map_err(into_io_error)?;
+            Some(web::Data::new(Arc::new(provider)))
+        }
+        None => None,
+    };
 
-    // FIXME: configure via CLI/env
-    let mut key = [0; 32];
-    key[0] = 0;
-    key[1] = 1;
-    key[2] = 1;
-    key[3] = 2;
-    key[4] = 3;
-    key[5] = 5;
-    key[6] = 8;
-    key[7] = 13;
+    let cookie_keys = Arc::new(cookie_key::CookieKeys::from_opts(&opts)?);
+    let cookie_secure = opts.cookie_secure;
+    let cookie_same_site = opts.cookie_same_site;
+    if cookie_same_site == SameSite::None && !cookie_secure {
+        return Err(into_io_error(
+            "`SameSite=None` session cookies need `--cookie-secure true`, \
+            browsers reject them otherwise.",
+        ));
+    }
 
     HttpServer::new(move || {
+        let cookie_keys = cookie_keys.clone();
         App::new()
             .wrap(Logger::default())
-            .wrap(
-                CookieSession::signed(&key)
-                    .name("phi")
-                    .secure(false)
-                    .http_only(true)
-                    .same_site(SameSite::Lax)
-                    .path("/"),
-            )
-            .app_data(poker_data.clone())
+            .wrap(cookie_keys.session())
+            // Registered after the session so it gets to the cookie first.
+            .wrap_fn(move |mut req, srv| {
+                let resigned = cookie_keys.upgrade(&mut req);
+                let res = srv.call(req);
+                let cookie_keys = cookie_keys.clone();
+                async move {
+                    let mut res = res.await?;
+                    if let Some(resigned) = resigned {
+                        cookie_keys.reissue(&mut res, resigned);
+                    }
+                    Ok(res)
+                }
+            })
+            .app_data(registry_data.clone())
             .app_data(schema_data.clone())
+            .app_data(credentials_data.clone())
             .configure(gql::configure)
+            .configure(|cfg| {
+                if let Some(oidc_data) = &oidc_data {
+                    cfg.app_data(oidc_data.clone());
+                    oidc::configure(cfg);
+                }
+            })
             .configure(spa::configure)
     })
     .bind(opts.http_addr)?
     .run()
-    .await
+    .await?;
+
+    registry.shutdown();
+
+    // Don't lose whatever changed since the last debounced write.
+    storage.flush().map_err(into_io_error)
+}
+
+fn into_io_error<E>(e: E) -> std::io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    std::io::Error::other(e)
 }
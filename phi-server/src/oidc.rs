@@ -0,0 +1,931 @@
+//! Signing in with an OpenID Connect provider.
+//!
+//! Uses the authorization code flow with PKCE. ID tokens are checked against
+//! the provider's published keys, and the provider has to be reached over
+//! https unless `--oidc-insecure` says otherwise, which is only meant for a
+//! local mock provider.
+//!
+//! Signed in players get a player id derived from their subject, so they keep
+//! it across devices and sessions, and the display name from their claims.
+
+use crate::cli::Opt;
+use crate::poker::PlayerId;
+use actix_session::Session;
+use actix_web::http::{header, StatusCode};
+use actix_web::{guard, web, HttpResponse, ResponseError, Result};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Session key for the sign in waiting on the provider.
+const LOGIN_KEY: &str = "oidc_login";
+/// Session key holding the `AdminGroupGrant`.
+const ADMIN_KEY: &str = "oidc_admin";
+/// Clocks drift, the provider's and ours included.
+const CLOCK_SKEW_SECS: u64 = 60;
+
+#[derive(Debug)]
+pub enum OidcError {
+    /// The provider couldn't be reached or sent something unreadable.
+    Request(String),
+    /// The provider turned the player away.
+    Denied(String),
+    /// The ID token doesn't check out.
+    InvalidToken(&'static str),
+    /// The callback doesn't match a sign in started from this session.
+    State,
+    /// The provider isn't behind https.
+    Insecure(String),
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OidcError::Request(e) => write!(f, "Failed to talk to the identity provider: {}", e),
+            OidcError::Denied(e) => write!(f, "The identity provider refused the sign in: {}", e),
+            OidcError::InvalidToken(e) => write!(f, "Invalid ID token: {}", e),
+            OidcError::State => write!(f, "No sign in is in progress, please try again."),
+            OidcError::Insecure(url) => write!(
+                f,
+                "`{}` isn't https. Pass `--oidc-insecure` to allow it anyway.",
+                url
+            ),
+        }
+    }
+}
+
+impl std::error::Error for OidcError {}
+
+impl ResponseError for OidcError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            OidcError::Request(_) | OidcError::InvalidToken(_) | OidcError::Insecure(_) => {
+                StatusCode::BAD_GATEWAY
+            }
+            OidcError::Denied(_) => StatusCode::FORBIDDEN,
+            OidcError::State => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+fn request_error(e: impl fmt::Display) -> OidcError {
+    OidcError::Request(e.to_string())
+}
+
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_url: String,
+    pub scopes: String,
+    pub groups_claim: String,
+    pub admin_group: Option<String>,
+    /// How long the admin group membership from signing in is trusted.
+    pub admin_max_age: Duration,
+    /// Allows talking to the provider over plain http.
+    pub insecure: bool,
+}
+
+impl OidcConfig {
+    fn require_https(&self, url: &str) -> Result<(), OidcError> {
+        if self.insecure || url.starts_with("https://") {
+            Ok(())
+        } else {
+            Err(OidcError::Insecure(url.to_string()))
+        }
+    }
+}
+
+impl OidcConfig {
+    /// The config for the provider, if one is set up.
+    pub fn from_opts(opts: &Opt) -> Option<OidcConfig> {
+        Some(OidcConfig {
+            issuer: opts.oidc_issuer.clone()?,
+            client_id: opts.oidc_client_id.clone()?,
+            client_secret: opts.oidc_client_secret.clone(),
+            redirect_url: opts.oidc_redirect_url.clone()?,
+            scopes: opts.oidc_scopes.clone(),
+            groups_claim: opts.oidc_groups_claim.clone(),
+            admin_group: opts.oidc_admin_group.clone(),
+            admin_max_age: Duration::from_secs(opts.oidc_admin_max_age_secs),
+            insecure: opts.oidc_insecure,
+        })
+    }
+}
+
+/// The parts of the provider metadata used here.
+#[derive(Debug, Deserialize)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+/// The claims used here. The audience and expiry are checked while decoding.
+#[derive(Debug, Deserialize)]
+struct Claims {
+    iss: String,
+    sub: String,
+    nonce: Option<String>,
+    name: Option<String>,
+    preferred_username: Option<String>,
+    email: Option<String>,
+    /// Where the groups claim ends up, whatever it's called.
+    #[serde(flatten)]
+    other: HashMap<String, serde_json::Value>,
+}
+
+/// Membership of the admin group as of signing in, good until `expires_at`
+/// so players taken out of the group don't stay admins for good.
+#[derive(Debug, Serialize, Deserialize)]
+struct AdminGroupGrant {
+    /// The provider that put the player in the group.
+    issuer: String,
+    /// Seconds since the unix epoch.
+    expires_at: u64,
+}
+
+/// A sign in waiting on the provider, kept in the session until the callback.
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingLogin {
+    state: String,
+    nonce: String,
+    verifier: String,
+    return_to: String,
+}
+
+async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, OidcError> {
+    let mut resp = awc::Client::default()
+        .get(url)
+        .send()
+        .await
+        .map_err(request_error)?;
+    if !resp.status().is_success() {
+        return Err(OidcError::Request(format!(
+            "{} answered {}",
+            url,
+            resp.status()
+        )));
+    }
+    resp.json().await.map_err(request_error)
+}
+
+pub struct Provider {
+    config: OidcConfig,
+    metadata: Metadata,
+    /// The keys ID tokens are signed with, fetched again when a token turns
+    /// up signed with a key that isn't in here.
+    keys: RwLock<JwkSet>,
+}
+
+impl Provider {
+    /// Looks up the provider's endpoints and signing keys.
+    pub async fn discover(config: OidcConfig) -> Result<Provider, OidcError> {
+        config.require_https(&config.issuer)?;
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer.trim_end_matches('/')
+        );
+        let metadata: Metadata = get_json(&url).await?;
+        if metadata.issuer.trim_end_matches('/') != config.issuer.trim_end_matches('/') {
+            return Err(OidcError::Request(format!(
+                "the provider calls itself `{}`",
+                metadata.issuer
+            )));
+        }
+        config.require_https(&metadata.authorization_endpoint)?;
+        config.require_https(&metadata.token_endpoint)?;
+        config.require_https(&metadata.jwks_uri)?;
+        let keys = get_json(&metadata.jwks_uri).await?;
+        Ok(Provider {
+            config,
+            metadata,
+            keys: RwLock::new(keys),
+        })
+    }
+
+    /// Whether the session was signed in by this provider as a member of the
+    /// admin group.
+    pub fn grants_admin(&self, session: &Session) -> bool {
+        if self.config.admin_group.is_none() {
+            return false;
+        }
+        let grant = match session.get::<AdminGroupGrant>(ADMIN_KEY) {
+            Ok(grant) => grant,
+            Err(e) => {
+                log::warn!("Ignoring unreadable admin group grant: {e}");
+                None
+            }
+        };
+        match grant {
+            Some(grant) if grant.expires_at > unix_now() => grant.issuer == self.metadata.issuer,
+            Some(_) => {
+                log::debug!("Admin group grant expired, the player needs to sign in again");
+                session.remove(ADMIN_KEY);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn authorize_url(&self, pending: &PendingLogin) -> Result<String> {
+        let challenge = encode(&Sha256::digest(pending.verifier.as_bytes()));
+        let params = serde_urlencoded::to_string([
+            ("response_type", "code"),
+            ("client_id", self.config.client_id.as_str()),
+            ("redirect_uri", self.config.redirect_url.as_str()),
+            ("scope", self.config.scopes.as_str()),
+            ("state", pending.state.as_str()),
+            ("nonce", pending.nonce.as_str()),
+            ("code_challenge", challenge.as_str()),
+            ("code_challenge_method", "S256"),
+        ])
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+        let endpoint = &self.metadata.authorization_endpoint;
+        let separator = if endpoint.contains('?') { '&' } else { '?' };
+        Ok(format!("{}{}{}", endpoint, separator, params))
+    }
+
+    /// Trades the authorization code for the player's claims.
+    async fn exchange(&self, code: &str, pending: &PendingLogin) -> Result<Claims, OidcError> {
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.config.redirect_url.as_str()),
+            ("client_id", self.config.client_id.as_str()),
+            ("code_verifier", pending.verifier.as_str()),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret.as_str()));
+        }
+        let mut resp = awc::Client::default()
+            .post(&self.metadata.token_endpoint)
+            .send_form(&form)
+            .await
+            .map_err(request_error)?;
+        if !resp.status().is_success() {
+            return Err(OidcError::Denied(format!(
+                "the token endpoint answered {}",
+                resp.status()
+            )));
+        }
+        let token: TokenResponse = resp.json().await.map_err(request_error)?;
+        self.verify(&token.id_token, &pending.nonce).await
+    }
+
+    /// The claims in the ID token, once its signature and claims check out.
+    async fn verify(&self, id_token: &str, nonce: &str) -> Result<Claims, OidcError> {
+        let header = jsonwebtoken::decode_header(id_token)
+            .map_err(|_| OidcError::InvalidToken("it isn't a JWT"))?;
+        let kid = header.kid.as_deref();
+        let (key, alg) = match self.decoding_key(kid) {
+            Some(key) => key,
+            None => {
+                // The provider may have rotated its keys since startup.
+                let keys = get_json(&self.metadata.jwks_uri).await?;
+                *self.keys.write().unwrap() = keys;
+                self.decoding_key(kid)
+                    .ok_or(OidcError::InvalidToken("it was signed with an unknown key"))?
+            }
+        };
+
+        // The token names its own algorithm, so it doesn't get a say.
+        let mut validation = Validation::new(alg);
+        validation.set_issuer(&[&self.metadata.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.leeway = CLOCK_SKEW_SECS;
+        let claims = jsonwebtoken::decode::<Claims>(id_token, &key, &validation)
+            .map_err(|e| {
+                OidcError::InvalidToken(match e.kind() {
+                    ErrorKind::ExpiredSignature => "it has expired",
+                    ErrorKind::InvalidIssuer => "it was issued by someone else",
+                    ErrorKind::InvalidAudience => "it was issued to another client",
+                    ErrorKind::Json(_) | ErrorKind::MissingRequiredClaim(_) => {
+                        "its claims are unreadable"
+                    }
+                    _ => "its signature doesn't check out",
+                })
+            })?
+            .claims;
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(OidcError::InvalidToken("it was issued for another sign in"));
+        }
+        Ok(claims)
+    }
+
+    /// The key with the given id, or the only key when the token doesn't say,
+    /// along with the algorithm it signs with.
+    fn decoding_key(&self, kid: Option<&str>) -> Option<(DecodingKey, Algorithm)> {
+        let keys = self.keys.read().unwrap();
+        let jwk = match kid {
+            Some(kid) => keys.find(kid),
+            None if keys.keys.len() == 1 => keys.keys.first(),
+            None => None,
+        }?;
+        let alg = signing_algorithm(jwk)?;
+        Some((DecodingKey::from_jwk(jwk).ok()?, alg))
+    }
+
+    fn is_admin(&self, claims: &Claims) -> bool {
+        let group = match &self.config.admin_group {
+            Some(group) => group,
+            None => return false,
+        };
+        match claims.other.get(&self.config.groups_claim) {
+            Some(serde_json::Value::Array(groups)) => {
+                groups.iter().any(|g| g.as_str() == Some(group.as_str()))
+            }
+            Some(serde_json::Value::String(g)) => g == group,
+            _ => false,
+        }
+    }
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Enough randomness for the state, nonce and PKCE verifier.
+fn random_token() -> String {
+    let mut bytes = [0; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    encode(&bytes)
+}
+
+/// Only paths on this server, so the sign in can't be used to send players
+/// elsewhere.
+fn local_path(return_to: Option<&str>) -> String {
+    match return_to {
+        Some(path) if path.starts_with('/') && !path.starts_with("//") && !path.contains('\\') => {
+            path.to_string()
+        }
+        _ => String::from("/"),
+    }
+}
+
+fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginQuery {
+    return_to: Option<String>,
+}
+
+/// Sends the player off to the provider to sign in.
+async fn login(
+    session: Session,
+    provider: web::Data<Arc<Provider>>,
+    query: web::Query<LoginQuery>,
+) -> Result<HttpResponse> {
+    let pending = PendingLogin {
+        state: random_token(),
+        nonce: random_token(),
+        verifier: random_token(),
+        return_to: local_path(query.return_to.as_deref()),
+    };
+    let url = provider.authorize_url(&pending)?;
+    session.insert(LOGIN_KEY, &pending)?;
+    Ok(redirect(&url))
+}
+
+#[derive(Debug, Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Where the provider sends the player back to.
+async fn callback(
+    session: Session,
+    provider: web::Data<Arc<Provider>>,
+    query: web::Query<CallbackQuery>,
+) -> Result<HttpResponse> {
+    let pending = session
+        .get::<PendingLogin>(LOGIN_KEY)?
+        .ok_or(OidcError::State)?;
+    session.remove(LOGIN_KEY);
+    if query.state.as_deref() != Some(pending.state.as_str()) {
+        return Err(OidcError::State.into());
+    }
+    if let Some(error) = &query.error {
+        let reason = query.error_description.as_ref().unwrap_or(error);
+        return Err(OidcError::Denied(reason.clone()).into());
+    }
+    let code = query
+        .code
+        .as_deref()
+        .ok_or_else(|| OidcError::Denied(String::from("no authorization code was sent")))?;
+    let claims = provider.exchange(code, &pending).await?;
+
+    let id = PlayerId::new_v5(
+        &PlayerId::NAMESPACE_URL,
+        format!("{}#{}", claims.iss, claims.sub).as_bytes(),
+    );
+    let name = claims
+        .name
+        .clone()
+        .or_else(|| claims.preferred_username.clone())
+        .or_else(|| claims.email.clone())
+        .unwrap_or_else(|| String::from(crate::gql::DEFAULT_PLAYER_NAME));
+    log::debug!("Player signed in: sub={} id={id} name={name}", claims.sub);
+    // Whatever the last player was allowed to do stays with them.
+    crate::gql::forget_admin_grants(&session);
+    session.insert("player_id", id)?;
+    session.insert("player_name", name)?;
+    if provider.is_admin(&claims) {
+        let grant = AdminGroupGrant {
+            issuer: claims.iss.clone(),
+            expires_at: unix_now() + provider.config.admin_max_age.as_secs(),
+        };
+        session.insert(ADMIN_KEY, grant)?;
+    } else {
+        session.remove(ADMIN_KEY);
+    }
+    Ok(redirect(&pending.return_to))
+}
+
+/// Forgets who the player is, admin grants included.
+///
+/// Only takes a POST, so other sites can't sign players out with a link.
+async fn logout(session: Session) -> HttpResponse {
+    session.purge();
+    redirect("/")
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/auth/login").guard(guard::Get()).to(login))
+        .service(
+            web::resource("/auth/callback")
+                .guard(guard::Get())
+                .to(callback),
+        )
+        .service(
+            web::resource("/auth/logout")
+                .guard(guard::Post())
+                .to(logout),
+        );
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// The algorithm a published key signs with: the one it names, as long as
+/// that suits the type of key, or the usual one for its type otherwise.
+///
+/// Shared secrets are never taken, since anyone can read the key set.
+fn signing_algorithm(jwk: &Jwk) -> Option<Algorithm> {
+    use Algorithm::*;
+    let allowed: &[Algorithm] = match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => &[RS256, RS384, RS512, PS256, PS384, PS512],
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => &[ES256],
+            EllipticCurve::P384 => &[ES384],
+            _ => &[],
+        },
+        AlgorithmParameters::OctetKeyPair(params) if params.curve == EllipticCurve::Ed25519 => {
+            &[EdDSA]
+        }
+        _ => &[],
+    };
+    match jwk.common.algorithm {
+        Some(alg) => allowed.contains(&alg).then_some(alg),
+        None => allowed.first().copied(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_session::CookieSession;
+    use actix_web::cookie::Cookie;
+    use actix_web::{test, App, HttpServer};
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use serde_json::json;
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// The mock provider signs with this key, published under `KEY_ID`.
+    const KEY_PEM: &[u8] = include_bytes!("../testdata/oidc_key.pem");
+    const KEY_ID: &str = "test";
+    const KEY_N: &str = "zQg-cK3IKN7jey2BWcq7KnYuoAX9J63FI1Q_xkWCIdb-BkI4irYXcOTKNf9E1y7KcuD-thB9Zl1VbLB_pw-gmD7LR-3fFeizImcCwWmENR_wbUtP6LM-dg6CRxeuCJIrzgZRjHDDAKdQ9zFge2RVshTsSTudm9VXeFTEBXDRrZbCFhjuMzPd2hXkzGrba6suGp7UIxPpPNEmeb-d0L0MdIQsNIB2gNxKYcCUQ0SaZQl9NsoMFhUgw3N8VAxMizP1WbkFgWAjzXPAHJNce6wDE01hU5YVP1fGe3y8Q9mxrTWYIs3BrCmY0HWcXRKi1qp2WTwOa3VVoC4rFmeqv-b_Fw";
+    const CLIENT_ID: &str = "phi";
+    const ADMIN_GROUP: &str = "phi-admins";
+
+    /// A stand-in identity provider, serving discovery, keys and tokens.
+    struct MockProvider {
+        issuer: String,
+        /// The claims for the next ID token handed out.
+        claims: Mutex<serde_json::Value>,
+        /// Sign the next token with an HMAC instead of the published key.
+        forge: Mutex<bool>,
+        /// The PKCE verifier sent to the token endpoint.
+        verifier: Mutex<Option<String>>,
+    }
+
+    async fn discovery(mock: web::Data<Arc<MockProvider>>) -> HttpResponse {
+        HttpResponse::Ok().json(json!({
+            "issuer": mock.issuer,
+            "authorization_endpoint": format!("{}/authorize", mock.issuer),
+            "token_endpoint": format!("{}/token", mock.issuer),
+            "jwks_uri": format!("{}/jwks", mock.issuer),
+        }))
+    }
+
+    async fn jwks() -> HttpResponse {
+        HttpResponse::Ok().json(json!({
+            "keys": [{
+                "kty": "RSA",
+                "kid": KEY_ID,
+                "alg": "RS256",
+                "use": "sig",
+                "n": KEY_N,
+                "e": "AQAB",
+            }]
+        }))
+    }
+
+    async fn token(
+        mock: web::Data<Arc<MockProvider>>,
+        form: web::Form<HashMap<String, String>>,
+    ) -> HttpResponse {
+        *mock.verifier.lock().unwrap() = form.get("code_verifier").cloned();
+        let claims = mock.claims.lock().unwrap().clone();
+        let id_token = if *mock.forge.lock().unwrap() {
+            let mut header = Header::new(Algorithm::HS256);
+            header.kid = Some(KEY_ID.to_string());
+            jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(b"guess"))
+        } else {
+            let mut header = Header::new(Algorithm::RS256);
+            header.kid = Some(KEY_ID.to_string());
+            let key = EncodingKey::from_rsa_pem(KEY_PEM).unwrap();
+            jsonwebtoken::encode(&header, &claims, &key)
+        };
+        HttpResponse::Ok().json(json!({
+            "access_token": "unused",
+            "token_type": "Bearer",
+            "id_token": id_token.unwrap(),
+        }))
+    }
+
+    async fn start_mock_provider() -> Arc<MockProvider> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mock = Arc::new(MockProvider {
+            issuer: format!("http://{}", listener.local_addr().unwrap()),
+            claims: Mutex::new(json!({})),
+            forge: Mutex::new(false),
+            verifier: Mutex::new(None),
+        });
+        let data = web::Data::new(mock.clone());
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to(discovery),
+                )
+                .route("/jwks", web::get().to(jwks))
+                .route("/token", web::post().to(token))
+        })
+        .workers(1)
+        .disable_signals()
+        .listen(listener)
+        .unwrap()
+        .run();
+        actix_rt::spawn(server);
+        mock
+    }
+
+    fn config(issuer: &str, insecure: bool) -> OidcConfig {
+        OidcConfig {
+            issuer: issuer.to_string(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: None,
+            redirect_url: String::from("http://phi.test/auth/callback"),
+            scopes: String::from("openid profile"),
+            groups_claim: String::from("groups"),
+            admin_group: Some(ADMIN_GROUP.to_string()),
+            admin_max_age: Duration::from_secs(3600),
+            insecure,
+        }
+    }
+
+    fn claims(mock: &MockProvider, nonce: &str, groups: &[&str]) -> serde_json::Value {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        json!({
+            "iss": mock.issuer,
+            "sub": "alice-123",
+            "aud": CLIENT_ID,
+            "exp": now + 300,
+            "iat": now,
+            "nonce": nonce,
+            "name": "Alice",
+            "groups": groups,
+        })
+    }
+
+    /// Echoes who the session belongs to.
+    async fn whoami(session: Session, provider: web::Data<Arc<Provider>>) -> HttpResponse {
+        HttpResponse::Ok().json(json!({
+            "player_id": session.get::<PlayerId>("player_id").unwrap(),
+            "player_name": session.get::<String>("player_name").unwrap(),
+            "admin": provider.grants_admin(&session),
+        }))
+    }
+
+    fn session_cookie<B>(resp: &actix_web::dev::ServiceResponse<B>) -> Cookie<'static> {
+        resp.response()
+            .cookies()
+            .find(|cookie| cookie.name() == "actix-session")
+            .expect("session cookie")
+            .into_owned()
+    }
+
+    fn location<B>(resp: &actix_web::dev::ServiceResponse<B>) -> &str {
+        resp.headers()
+            .get(header::LOCATION)
+            .expect("redirect")
+            .to_str()
+            .unwrap()
+    }
+
+    /// The query string the player was sent to the provider with.
+    fn authorize_params<B>(resp: &actix_web::dev::ServiceResponse<B>) -> HashMap<String, String> {
+        let location = location(resp);
+        let (_, query) = location.split_once('?').unwrap();
+        serde_urlencoded::from_str(query).unwrap()
+    }
+
+    /// A mock provider, and an app that signs in with it, optionally with
+    /// the config tweaked. A macro, since the app's type can't be named.
+    macro_rules! setup {
+        () => {
+            setup!(|config| config)
+        };
+        ($tweak:expr) => {{
+            let mock = start_mock_provider().await;
+            let tweak: fn(OidcConfig) -> OidcConfig = $tweak;
+            let provider = Arc::new(
+                Provider::discover(tweak(config(&mock.issuer, true)))
+                    .await
+                    .unwrap(),
+            );
+            let app = test::init_service(
+                App::new()
+                    .wrap(CookieSession::signed(&[0; 32]).secure(false))
+                    .app_data(web::Data::new(provider))
+                    .configure(configure)
+                    .route("/whoami", web::get().to(whoami)),
+            )
+            .await;
+            (mock, app)
+        }};
+    }
+
+    #[actix_rt::test]
+    async fn signs_in_and_maps_the_admin_group() {
+        let (mock, app) = setup!();
+
+        let req = test::TestRequest::get()
+            .uri("/auth/login?return_to=/rooms/default")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        let params = authorize_params(&resp);
+        assert_eq!(params["client_id"], CLIENT_ID);
+        assert_eq!(params["code_challenge_method"], "S256");
+        let cookie = session_cookie(&resp);
+
+        *mock.claims.lock().unwrap() = claims(&mock, &params["nonce"], &["devs", ADMIN_GROUP]);
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/auth/callback?code=abc&state={}",
+                params["state"]
+            ))
+            .cookie(cookie)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(location(&resp), "/rooms/default");
+        let cookie = session_cookie(&resp);
+
+        // The token endpoint got the verifier matching the challenge.
+        let verifier = mock.verifier.lock().unwrap().clone().unwrap();
+        assert_eq!(
+            encode(&Sha256::digest(verifier.as_bytes())),
+            params["code_challenge"]
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/whoami")
+            .cookie(cookie)
+            .to_request();
+        let who: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let id = PlayerId::new_v5(
+            &PlayerId::NAMESPACE_URL,
+            format!("{}#alice-123", mock.issuer).as_bytes(),
+        );
+        assert_eq!(who["player_id"], json!(id));
+        assert_eq!(who["player_name"], "Alice");
+        assert_eq!(who["admin"], true);
+    }
+
+    #[actix_rt::test]
+    async fn players_outside_the_admin_group_are_not_admins() {
+        let (mock, app) = setup!();
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get().uri("/auth/login").to_request(),
+        )
+        .await;
+        let params = authorize_params(&resp);
+        *mock.claims.lock().unwrap() = claims(&mock, &params["nonce"], &["devs"]);
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/auth/callback?code=abc&state={}",
+                params["state"]
+            ))
+            .cookie(session_cookie(&resp))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+        assert_eq!(location(&resp), "/");
+
+        let req = test::TestRequest::get()
+            .uri("/whoami")
+            .cookie(session_cookie(&resp))
+            .to_request();
+        let who: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(who["player_name"], "Alice");
+        assert_eq!(who["admin"], false);
+    }
+
+    #[actix_rt::test]
+    async fn the_admin_group_has_to_be_checked_again_once_the_grant_expires() {
+        let (mock, app) = setup!(|config| OidcConfig {
+            admin_max_age: Duration::ZERO,
+            ..config
+        });
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get().uri("/auth/login").to_request(),
+        )
+        .await;
+        let params = authorize_params(&resp);
+        *mock.claims.lock().unwrap() = claims(&mock, &params["nonce"], &[ADMIN_GROUP]);
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/auth/callback?code=abc&state={}",
+                params["state"]
+            ))
+            .cookie(session_cookie(&resp))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+
+        let req = test::TestRequest::get()
+            .uri("/whoami")
+            .cookie(session_cookie(&resp))
+            .to_request();
+        let who: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(who["player_name"], "Alice");
+        assert_eq!(who["admin"], false);
+    }
+
+    #[actix_rt::test]
+    async fn rejects_a_state_mismatch() {
+        let (mock, app) = setup!();
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get().uri("/auth/login").to_request(),
+        )
+        .await;
+        let params = authorize_params(&resp);
+        *mock.claims.lock().unwrap() = claims(&mock, &params["nonce"], &[ADMIN_GROUP]);
+        let req = test::TestRequest::get()
+            .uri("/auth/callback?code=abc&state=forged")
+            .cookie(session_cookie(&resp))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(mock.verifier.lock().unwrap().is_none());
+    }
+
+    #[actix_rt::test]
+    async fn rejects_a_nonce_mismatch() {
+        let (mock, app) = setup!();
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get().uri("/auth/login").to_request(),
+        )
+        .await;
+        let params = authorize_params(&resp);
+        *mock.claims.lock().unwrap() = claims(&mock, "replayed", &[ADMIN_GROUP]);
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/auth/callback?code=abc&state={}",
+                params["state"]
+            ))
+            .cookie(session_cookie(&resp))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[actix_rt::test]
+    async fn rejects_a_token_not_signed_with_the_published_key() {
+        let (mock, app) = setup!();
+
+        let resp = test::call_service(
+            &app,
+            test::TestRequest::get().uri("/auth/login").to_request(),
+        )
+        .await;
+        let params = authorize_params(&resp);
+        *mock.claims.lock().unwrap() = claims(&mock, &params["nonce"], &[ADMIN_GROUP]);
+        *mock.forge.lock().unwrap() = true;
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/auth/callback?code=abc&state={}",
+                params["state"]
+            ))
+            .cookie(session_cookie(&resp))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[actix_rt::test]
+    async fn requires_https_unless_insecure() {
+        let mock = start_mock_provider().await;
+        match Provider::discover(config(&mock.issuer, false)).await {
+            Err(OidcError::Insecure(url)) => assert_eq!(url, mock.issuer),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("discovered a provider over plain http"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn logout_only_takes_a_post() {
+        let (_mock, app) = setup!();
+
+        let req = test::TestRequest::get().uri("/auth/logout").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let req = test::TestRequest::post().uri("/auth/logout").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn the_signing_algorithm_comes_from_the_key() {
+        let jwk = |extra: serde_json::Value| -> Jwk {
+            let mut key = json!({ "kty": "RSA", "n": KEY_N, "e": "AQAB" });
+            key.as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            serde_json::from_value(key).unwrap()
+        };
+        assert_eq!(signing_algorithm(&jwk(json!({}))), Some(Algorithm::RS256));
+        assert_eq!(
+            signing_algorithm(&jwk(json!({ "alg": "PS384" }))),
+            Some(Algorithm::PS384)
+        );
+        // An RSA key can't be used as an HMAC secret, whatever it claims.
+        assert_eq!(signing_algorithm(&jwk(json!({ "alg": "HS256" }))), None);
+        let secret: Jwk =
+            serde_json::from_value(json!({ "kty": "oct", "k": "c2VjcmV0", "alg": "HS256" }))
+                .unwrap();
+        assert_eq!(signing_algorithm(&secret), None);
+    }
+}
@@ -1,63 +1,253 @@
+use crate::deck::{Card, Deck, Decks};
+use crate::rooms::RoomId;
+use crate::stats::Results;
+use crate::storage::{Storage, StorageError};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::str::FromStr;
-use std::sync::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime};
 use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
 use uuid::Uuid;
 
-/// The names of the cards in the planning poker deck.
-pub const FIB_DECK: [&str; 12] = [
-    "0", "1", "2", "3", "5", "8", "13", "21", "100", "∞", "?", "☕",
-];
-pub const DAYS_DECK: [&str; 9] = ["0.5", "1", "1.5", "2", "3", "5", "∞", "?", "☕"];
-
-#[derive(Debug)]
-pub enum DeckType {
-    Fibonacci,
-    Days,
-}
-
-impl FromStr for DeckType {
-    type Err = String;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "fib" | "" => Ok(DeckType::Fibonacci),
-            "days" => Ok(DeckType::Days),
-            _ => Err(format!("Invalid deck type: `{}`. Use `fib` or `days`.", s)),
-        }
-    }
-}
-
 /// Stable handle for identifying players, regardless of what the display name
 /// is.
 pub type PlayerId = Uuid;
 /// Certain features are only enabled for players who know the secret key for
 /// the session.
 pub type AdminKey = String;
+/// Stable handle for identifying stories in the queue.
+pub type StoryId = Uuid;
+/// Stable handle for identifying rounds in the history.
+pub type RoundId = Uuid;
+/// Stable handle for lifting bans.
+pub type BanId = Uuid;
+
+/// Players who fail to send a heartbeat within this time will be shown as
+/// being idle, unless their presence is tracked by a websocket.
+pub const PLAYER_IDLE_THRESHOLD: Duration = Duration::from_secs(30);
+/// How often each room looks for idle and disconnected players.
+const REAPER_INTERVAL: Duration = Duration::from_secs(5);
+/// Subscribers are warned when a round timer has this much time left.
+const TIMER_WARNINGS: [Duration; 3] = [
+    Duration::from_secs(60),
+    Duration::from_secs(30),
+    Duration::from_secs(10),
+];
+
+/// How a player takes part in the game.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Voter,
+    /// Listens in without voting.
+    Spectator,
+    /// Runs the session, and votes along with everyone else.
+    Facilitator,
+}
+
+impl Role {
+    pub fn votes(self) -> bool {
+        self != Role::Spectator
+    }
+}
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct Player {
     pub id: PlayerId,
     /// The name displayed with the cards.
     pub name: String,
-    /// Index into the card data, `CARDS`.
+    /// Index into the cards of the deck for the room.
     pub selected_card: Option<usize>,
     pub last_heartbeat: SystemTime,
+    #[serde(default)]
+    pub role: Role,
 }
 
 impl Player {
-    pub fn new(name: String, id: PlayerId) -> Player {
+    pub fn new(name: String, id: PlayerId, role: Role) -> Player {
         Player {
             id,
             name,
             selected_card: None,
             last_heartbeat: SystemTime::now(),
+            role,
+        }
+    }
+
+    /// How long since the last heartbeat. Zero if the clock went backwards.
+    fn silence(&self) -> Duration {
+        self.last_heartbeat.elapsed().unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoryStatus {
+    /// Waiting its turn in the queue.
+    Pending,
+    /// The story the table is voting on right now.
+    Estimating,
+    Estimated,
+    Skipped,
+}
+
+/// Something to be estimated, like a ticket from the issue tracker.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Story {
+    pub id: StoryId,
+    pub title: String,
+    pub description: Option<String>,
+    /// Where to read more, like a link to the ticket.
+    pub link: Option<String>,
+    pub status: StoryStatus,
+    /// The estimate the facilitator accepted.
+    #[serde(default)]
+    pub estimate: Option<Card>,
+}
+
+impl Story {
+    pub fn new(title: String, description: Option<String>, link: Option<String>) -> Story {
+        Story {
+            id: StoryId::new_v4(),
+            title,
+            description,
+            link,
+            status: StoryStatus::Pending,
+            estimate: None,
         }
     }
 }
 
+/// What a player had picked when a round ended.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct RoundVote {
+    pub player_id: PlayerId,
+    /// The name of the player at the time.
+    pub name: String,
+    /// `None` for players who sat at the table without voting.
+    pub card: Option<Card>,
+}
+
+/// A completed call/reset cycle.
+///
+/// Cards are copied out of the deck so the history still makes sense after
+/// the room switches decks.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Round {
+    pub id: RoundId,
+    pub story_id: Option<StoryId>,
+    pub story_title: Option<String>,
+    pub started_at: Option<SystemTime>,
+    pub called_at: Option<SystemTime>,
+    pub ended_at: SystemTime,
+    pub votes: Vec<RoundVote>,
+    pub final_estimate: Option<Card>,
+    /// Whether the room was anonymous when the round was played. Unknown for
+    /// rounds recorded before this was kept, which follow the room instead.
+    #[serde(default)]
+    pub anonymous: Option<bool>,
+}
+
+/// Keeps a player out of a room, by id, by name or both.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Ban {
+    pub id: BanId,
+    pub player_id: Option<PlayerId>,
+    pub name: Option<String>,
+    pub reason: Option<String>,
+    pub banned_at: SystemTime,
+}
+
+impl Ban {
+    /// Names are matched ignoring case and surrounding whitespace.
+    pub fn matches(&self, player_id: PlayerId, name: &str) -> bool {
+        self.player_id == Some(player_id)
+            || self
+                .name
+                .as_deref()
+                .map(|banned| banned.trim().eq_ignore_ascii_case(name.trim()))
+                .unwrap_or(false)
+    }
+}
+
+/// Tells a player they were taken out of the room by an admin.
+#[derive(Clone, Debug)]
+pub struct Kick {
+    pub player_id: PlayerId,
+    pub reason: Option<String>,
+    pub banned: bool,
+}
+
+/// How the table decides it agrees on an estimate. Only a numeric card can be
+/// agreed on, so a table that all picks "?" has not reached consensus.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum ConsensusRule {
+    /// Everybody picked the same card.
+    #[default]
+    Unanimous,
+    /// Every vote is on the same card or a neighbouring one.
+    Adjacent,
+    /// At least `percent` of the votes are on the same card.
+    Supermajority { percent: u8 },
+}
+
+/// Calls the cards once everybody has voted.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq)]
+pub struct AutoCall {
+    /// How long to wait before calling. A vote changing in the meantime
+    /// starts the wait over.
+    pub delay_ms: u64,
+}
+
+/// What happens when the round timer runs out.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimerExpiry {
+    /// The cards are called.
+    #[default]
+    Call,
+    /// The timer is marked as expired and the facilitator decides what to do.
+    Flag,
+}
+
+/// A countdown for the round in progress.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct RoundTimer {
+    pub started_at: SystemTime,
+    pub deadline: SystemTime,
+    pub on_expiry: TimerExpiry,
+    pub expired: bool,
+}
+
+impl RoundTimer {
+    /// Time left until the deadline. Zero once it has passed.
+    pub fn remaining(&self) -> Duration {
+        self.deadline
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    }
+}
+
+/// Per-room preferences set by the facilitator.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct RoomSettings {
+    #[serde(default)]
+    pub consensus_rule: ConsensusRule,
+    /// Off unless set.
+    #[serde(default)]
+    pub auto_call: Option<AutoCall>,
+    /// Keeps who picked what hidden after the call.
+    #[serde(default)]
+    pub anonymous: bool,
+    /// When set, players need the password or an invite to join.
+    #[serde(default)]
+    pub password_hash: Option<String>,
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
 pub struct GameState {
     /// All the players in the planning poker game.
@@ -65,36 +255,840 @@ pub struct GameState {
     /// While "calling" player card selections are *frozen* and revealed to all
     /// players.
     pub is_calling: bool,
+    /// The name of the deck in play. `None` means the server default.
+    #[serde(default)]
+    pub deck: Option<String>,
+    #[serde(default)]
+    pub settings: RoomSettings,
+    /// The queue of stories to estimate, in order.
+    #[serde(default)]
+    pub stories: Vec<Story>,
+    /// The story being voted on, if any.
+    #[serde(default)]
+    pub current_story: Option<StoryId>,
+    /// Rounds that have been played, oldest first.
+    #[serde(default)]
+    pub rounds: Vec<Round>,
+    /// When the round in progress began.
+    #[serde(default)]
+    pub round_started_at: Option<SystemTime>,
+    /// When the round in progress was called.
+    #[serde(default)]
+    pub called_at: Option<SystemTime>,
+    /// The estimate accepted for the round in progress.
+    #[serde(default)]
+    pub accepted_estimate: Option<Card>,
+    /// The countdown for the round in progress, if one was started.
+    #[serde(default)]
+    pub timer: Option<RoundTimer>,
+    /// Players who can't join the room.
+    #[serde(default)]
+    pub bans: Vec<Ban>,
+}
+
+impl GameState {
+    /// A copy of everything but the round history and the bans, which only
+    /// ever grow and are read on their own.
+    pub fn without_history(&self) -> GameState {
+        GameState {
+            players: self.players.clone(),
+            is_calling: self.is_calling,
+            deck: self.deck.clone(),
+            settings: self.settings.clone(),
+            stories: self.stories.clone(),
+            current_story: self.current_story,
+            rounds: Vec::new(),
+            round_started_at: self.round_started_at,
+            called_at: self.called_at,
+            accepted_estimate: self.accepted_estimate.clone(),
+            timer: self.timer.clone(),
+            bans: Vec::new(),
+        }
+    }
+
+    /// The ban keeping the player out, if there is one.
+    pub fn ban_for(&self, player_id: PlayerId, name: &str) -> Option<&Ban> {
+        self.bans.iter().find(|ban| ban.matches(player_id, name))
+    }
+
+    /// Clears every card selection and unfreezes the table.
+    ///
+    /// Rounds that were called are added to the history first, even if play
+    /// resumed since. `deck` is the deck the selections were made from.
+    pub fn reset_round(&mut self, deck: &Deck) {
+        if let Some(called_at) = self.called_at {
+            let story = self.current_story();
+            let round = Round {
+                id: RoundId::new_v4(),
+                story_id: story.map(|story| story.id),
+                story_title: story.map(|story| story.title.clone()),
+                started_at: self.round_started_at,
+                called_at: Some(called_at),
+                ended_at: SystemTime::now(),
+                votes: self
+                    .players
+                    .values()
+                    .filter(|player| player.role.votes())
+                    .map(|player| RoundVote {
+                        player_id: player.id,
+                        name: player.name.clone(),
+                        card: player
+                            .selected_card
+                            .and_then(|idx| deck.cards.get(idx))
+                            .cloned(),
+                    })
+                    .collect(),
+                final_estimate: self.accepted_estimate.take(),
+                anonymous: Some(self.settings.anonymous),
+            };
+            self.rounds.push(round);
+        }
+        for player in self.players.values_mut() {
+            player.selected_card = None;
+        }
+        self.is_calling = false;
+        self.called_at = None;
+        self.timer = None;
+        self.accepted_estimate = None;
+        self.round_started_at = Some(SystemTime::now());
+    }
+
+    /// Records the agreed estimate against the current story and the round in
+    /// progress.
+    pub fn accept_estimate(&mut self, card: Card) -> Option<&Story> {
+        self.accepted_estimate = Some(card.clone());
+        let id = self.current_story?;
+        let story = self.story_mut(id)?;
+        story.estimate = Some(card);
+        Some(&*story)
+    }
+
+    pub fn current_story(&self) -> Option<&Story> {
+        let id = self.current_story?;
+        self.stories.iter().find(|story| story.id == id)
+    }
+
+    pub fn story_mut(&mut self, id: StoryId) -> Option<&mut Story> {
+        self.stories.iter_mut().find(|story| story.id == id)
+    }
+
+    /// Marks the current story as estimated and moves on to the next pending
+    /// story in the queue, starting a fresh round.
+    ///
+    /// Returns the new current story, if there is one.
+    pub fn advance(&mut self, deck: &Deck) -> Option<&Story> {
+        if let Some(story) = self.current_story.and_then(|id| self.story_mut(id)) {
+            if story.status == StoryStatus::Estimating {
+                story.status = StoryStatus::Estimated;
+            }
+        }
+        self.start_next_story(deck)
+    }
+
+    /// Marks a story as skipped. When it's the current story, the next pending
+    /// story takes its place.
+    pub fn skip(&mut self, id: StoryId, deck: &Deck) -> Option<&Story> {
+        let story = self.story_mut(id)?;
+        story.status = StoryStatus::Skipped;
+        if self.current_story == Some(id) {
+            self.start_next_story(deck);
+        }
+        self.stories.iter().find(|story| story.id == id)
+    }
+
+    fn start_next_story(&mut self, deck: &Deck) -> Option<&Story> {
+        self.reset_round(deck);
+        let next = self
+            .stories
+            .iter_mut()
+            .find(|story| story.status == StoryStatus::Pending);
+        self.current_story = match next {
+            Some(story) => {
+                story.status = StoryStatus::Estimating;
+                Some(story.id)
+            }
+            None => None,
+        };
+        self.current_story()
+    }
 }
 
 pub struct PlaySession {
+    pub room_id: RoomId,
     pub admin_key: AdminKey,
     pub disconnect_timeout: Duration,
-    pub game_state: Mutex<GameState>,
+    storage: Arc<dyn Storage>,
     /// When the game state changes, this is used to notify subscribers.
     pub game_state_notifier: broadcast::Sender<()>,
-    pub deck: &'static [&'static str],
+    /// Fired when a call ends with the table in agreement.
+    pub consensus_notifier: broadcast::Sender<(Arc<Deck>, Results)>,
+    /// Carries each `GameEvent` along with its sequence number.
+    pub event_notifier: broadcast::Sender<(u64, GameEvent)>,
+    /// The sequence number of the last event. Held for the whole of each
+    /// update, so events go out in the same order as the changes they
+    /// describe.
+    sequence: Mutex<u64>,
+    /// Bumped with every vote, so a pending automatic call can tell it has
+    /// gone stale.
+    vote_generation: AtomicU64,
+    /// Every deck the room can switch to.
+    pub decks: Arc<Decks>,
+    /// Used when the game state doesn't name a deck, or names one that has
+    /// since gone away.
+    pub default_deck: Arc<Deck>,
+    /// The task sweeping out idle players, once started.
+    reaper: Mutex<Option<JoinHandle<()>>>,
+    /// The task counting down the round timer, while one is running, along
+    /// with when that timer was started.
+    timer_task: Mutex<Option<(SystemTime, JoinHandle<()>)>>,
+    /// Open websocket subscriptions for each player that has at least one.
+    ///
+    /// Presence for these players follows their sockets instead of their
+    /// heartbeats.
+    connections: Mutex<HashMap<PlayerId, usize>>,
+    /// Players whose last socket closed. They show as away straight away,
+    /// until they connect again or send a heartbeat.
+    closed: Mutex<HashSet<PlayerId>>,
+}
+
+/// A single change to the game state, for clients that would rather apply
+/// deltas than take the whole state each time.
+#[derive(Clone, Debug)]
+pub enum GameEvent {
+    PlayerJoined(Player),
+    PlayerLeft(PlayerId),
+    PlayerRenamed(PlayerId, String),
+    PlayerRoleChanged(PlayerId, Role),
+    /// The player went away or came back.
+    PlayerIdleChanged(PlayerId, bool),
+    /// Whether the player now has a card selected. The card itself stays
+    /// hidden until the call.
+    PlayerVoted(PlayerId, bool),
+    Called(Arc<Deck>, Results),
+    Resumed,
+    /// The round started over, clearing every selection.
+    Reset,
+    TimerStarted(RoundTimer),
+    /// The round timer is running low.
+    TimerWarning(Duration),
+    TimerExpired,
+    TimerCancelled,
+    /// The queue or the current story changed.
+    StoryChanged {
+        stories: Vec<Story>,
+        current_story: Option<StoryId>,
+    },
+    /// The deck in play or one of the room settings changed.
+    SettingsChanged(Arc<Deck>, RoomSettings),
+    /// A player was taken out of the room by an admin.
+    Kicked(Kick),
+}
+
+impl GameEvent {
+    pub fn story_changed(game_state: &GameState) -> GameEvent {
+        GameEvent::StoryChanged {
+            stories: game_state.stories.clone(),
+            current_story: game_state.current_story,
+        }
+    }
+
+    pub fn settings_changed(session: &PlaySession, game_state: &GameState) -> GameEvent {
+        GameEvent::SettingsChanged(session.deck(game_state), game_state.settings.clone())
+    }
+}
+
+/// Marks a player as present for as long as it is held.
+pub struct Presence {
+    session: Arc<PlaySession>,
+    player_id: PlayerId,
+}
+
+impl Drop for Presence {
+    fn drop(&mut self) {
+        self.session.disconnected(self.player_id);
+    }
 }
 
 impl PlaySession {
     pub fn new(
+        room_id: RoomId,
         admin_key: AdminKey,
-        deck_type: DeckType,
+        decks: Arc<Decks>,
+        default_deck: Arc<Deck>,
         disconnect_timeout: Duration,
+        storage: Arc<dyn Storage>,
     ) -> PlaySession {
         let (tx, _rx) = broadcast::channel(100);
+        let (consensus_tx, _rx) = broadcast::channel(16);
+        let (event_tx, _rx) = broadcast::channel(100);
         PlaySession {
+            room_id,
             admin_key,
             disconnect_timeout,
-            game_state: Default::default(),
+            storage,
             game_state_notifier: tx,
-            deck: match deck_type {
-                DeckType::Fibonacci => &FIB_DECK,
-                DeckType::Days => &DAYS_DECK,
-            },
+            consensus_notifier: consensus_tx,
+            event_notifier: event_tx,
+            sequence: Mutex::new(0),
+            vote_generation: AtomicU64::new(0),
+            decks,
+            default_deck,
+            reaper: Mutex::new(None),
+            timer_task: Mutex::new(None),
+            connections: Mutex::new(HashMap::new()),
+            closed: Mutex::new(HashSet::new()),
         }
     }
 
+    /// Whether the player should be shown as away.
+    ///
+    /// Players with a socket open are present and players whose last socket
+    /// closed are away. Everyone else goes by their last heartbeat.
+    pub fn is_idle(&self, player: &Player) -> bool {
+        !self.is_connected(player.id)
+            && (self.closed.lock().unwrap().contains(&player.id)
+                || player.silence() > PLAYER_IDLE_THRESHOLD)
+    }
+
+    fn is_connected(&self, player_id: PlayerId) -> bool {
+        self.connections.lock().unwrap().contains_key(&player_id)
+    }
+
+    /// Records a websocket opening for the player. They count as present
+    /// until the returned `Presence` is dropped.
+    pub fn connected(self: &Arc<Self>, player_id: PlayerId) -> Presence {
+        let first = {
+            let mut connections = self.connections.lock().unwrap();
+            let count = connections.entry(player_id).or_insert(0);
+            *count += 1;
+            *count == 1
+        };
+        if first {
+            log::debug!("Player {} connected to room {}", player_id, self.room_id);
+            let was_closed = self.closed.lock().unwrap().remove(&player_id);
+            let touched = self.touch(player_id, false, |player| {
+                was_closed || player.silence() > PLAYER_IDLE_THRESHOLD
+            });
+            if let Err(e) = touched {
+                log::error!("Failed to update presence for {}: {}", player_id, e);
+            }
+        }
+        Presence {
+            session: self.clone(),
+            player_id,
+        }
+    }
+
+    /// Once the last socket for a player closes, they show as away and the
+    /// disconnect countdown starts from now.
+    fn disconnected(&self, player_id: PlayerId) {
+        let last = {
+            let mut connections = self.connections.lock().unwrap();
+            match connections.get_mut(&player_id) {
+                Some(count) if *count > 1 => {
+                    *count -= 1;
+                    false
+                }
+                Some(_) => {
+                    connections.remove(&player_id);
+                    true
+                }
+                None => false,
+            }
+        };
+        if last {
+            log::debug!(
+                "Player {} disconnected from room {}",
+                player_id,
+                self.room_id
+            );
+            self.closed.lock().unwrap().insert(player_id);
+            // They had a socket open until now, so weren't idle.
+            if let Err(e) = self.touch(player_id, true, |_| false) {
+                log::error!("Failed to update presence for {}: {}", player_id, e);
+            }
+        }
+    }
+
+    /// Records a heartbeat from a client that doesn't hold a socket open.
+    /// Returns whether the player is in the room.
+    pub fn heartbeat(&self, player_id: PlayerId) -> Result<bool, StorageError> {
+        let was_closed = self.closed.lock().unwrap().remove(&player_id);
+        let connected = self.is_connected(player_id);
+        self.touch(player_id, false, |player| {
+            !connected && (was_closed || player.silence() > PLAYER_IDLE_THRESHOLD)
+        })
+    }
+
+    /// Resets the heartbeat for the player and lets everyone know when that
+    /// changes whether they're idle. Returns whether the player is in the
+    /// room.
+    fn touch(
+        &self,
+        player_id: PlayerId,
+        idle: bool,
+        was_idle: impl FnOnce(&Player) -> bool,
+    ) -> Result<bool, StorageError> {
+        let (known, changed) = self.update_and_emit(|game_state, events| {
+            let player = match game_state.players.get_mut(&player_id) {
+                Some(player) => player,
+                None => return (false, false),
+            };
+            let changed = was_idle(player) != idle;
+            player.last_heartbeat = SystemTime::now();
+            if changed {
+                events.push(GameEvent::PlayerIdleChanged(player_id, idle));
+            }
+            (true, changed)
+        })?;
+        if changed {
+            self.notify_subscribers();
+        }
+        Ok(known)
+    }
+
+    /// Starts sweeping the room for idle players in the background.
+    ///
+    /// The task holds a weak reference, so it winds down on its own once the
+    /// session is dropped.
+    pub fn start_reaper(self: &Arc<Self>) {
+        let session = Arc::downgrade(self);
+        let handle = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REAPER_INTERVAL);
+            let mut idle = HashSet::new();
+            loop {
+                interval.tick().await;
+                let session = match session.upgrade() {
+                    Some(session) => session,
+                    None => break,
+                };
+                match session.reap(&mut idle) {
+                    Ok(true) => {
+                        session.notify_subscribers();
+                        // Whoever is left might all have voted.
+                        session.maybe_auto_call();
+                    }
+                    Ok(false) => (),
+                    Err(e) => log::error!("Failed to reap room {}: {}", session.room_id, e),
+                }
+            }
+        });
+        if let Some(prev) = self.reaper.lock().unwrap().replace(handle) {
+            prev.abort();
+        }
+    }
+
+    /// Stops the reaper and any round timer.
+    pub fn stop_tasks(&self) {
+        if let Some(handle) = self.reaper.lock().unwrap().take() {
+            handle.abort();
+        }
+        if let Some((_, handle)) = self.timer_task.lock().unwrap().take() {
+            handle.abort();
+        }
+    }
+
+    /// Starts a countdown for the round in progress, replacing any running
+    /// one.
+    pub fn start_timer(
+        self: &Arc<Self>,
+        duration: Duration,
+        on_expiry: TimerExpiry,
+    ) -> Result<RoundTimer, StorageError> {
+        let now = SystemTime::now();
+        let timer = RoundTimer {
+            started_at: now,
+            deadline: now + duration,
+            on_expiry,
+            expired: false,
+        };
+        self.update_and_emit(|game_state, events| {
+            game_state.timer = Some(timer.clone());
+            events.push(GameEvent::TimerStarted(timer.clone()));
+        })?;
+        self.notify_subscribers();
+        self.watch_timer(timer.clone());
+        Ok(timer)
+    }
+
+    /// Stops the countdown. Returns whether there was one to stop.
+    pub fn cancel_timer(&self) -> Result<bool, StorageError> {
+        let cancelled = self.update_and_emit(|game_state, events| {
+            let cancelled = game_state.timer.take().is_some();
+            if cancelled {
+                events.push(GameEvent::TimerCancelled);
+            }
+            cancelled
+        })?;
+        if cancelled {
+            self.notify_subscribers();
+        }
+        Ok(cancelled)
+    }
+
+    /// Picks up a timer that was running when the server stopped.
+    pub fn resume_timer(self: &Arc<Self>) {
+        match self.read(|game_state| game_state.timer.clone()) {
+            Ok(timer) => {
+                if let Some(timer) = timer.filter(|timer| !timer.expired) {
+                    self.watch_timer(timer);
+                }
+            }
+            Err(e) => log::error!("Failed to resume timer for room {}: {}", self.room_id, e),
+        }
+    }
+
+    fn watch_timer(self: &Arc<Self>, timer: RoundTimer) {
+        let session = Arc::downgrade(self);
+        let started_at = timer.started_at;
+        // The deadline is kept as wall clock time so it survives a restart,
+        // but counted down on tokio's clock from here on.
+        let remaining = timer.remaining();
+        let deadline = tokio::time::Instant::now() + remaining;
+        let handle = tokio::spawn(async move {
+            for warning in TIMER_WARNINGS.iter().copied() {
+                if remaining <= warning {
+                    continue;
+                }
+                tokio::time::sleep_until(deadline - warning).await;
+                match session.upgrade() {
+                    Some(session) if session.timer_is_current(&timer) => {
+                        session.notify_subscribers();
+                        session.emit(GameEvent::TimerWarning(warning));
+                    }
+                    _ => return,
+                }
+            }
+            tokio::time::sleep_until(deadline).await;
+            if let Some(session) = session.upgrade() {
+                session.expire_timer(&timer);
+            }
+        });
+        if let Some((_, prev)) = self
+            .timer_task
+            .lock()
+            .unwrap()
+            .replace((started_at, handle))
+        {
+            prev.abort();
+        }
+    }
+
+    /// Whether `timer` is still the one running for the round. Resets and
+    /// calls leave timers behind that should go quiet.
+    fn timer_is_current(&self, timer: &RoundTimer) -> bool {
+        self.read(|game_state| {
+            !game_state.is_calling
+                && game_state.timer.as_ref().map(|t| t.started_at) == Some(timer.started_at)
+        })
+        .unwrap_or(false)
+    }
+
+    /// Marks the timer as expired, calling the cards in the same update when
+    /// that's what the timer is for.
+    fn expire_timer(&self, timer: &RoundTimer) {
+        let expired = self.update_and_emit(|game_state, events| {
+            match &mut game_state.timer {
+                Some(current)
+                    if current.started_at == timer.started_at
+                        && !current.expired
+                        && !game_state.is_calling =>
+                {
+                    current.expired = true;
+                }
+                _ => return None,
+            }
+            events.push(GameEvent::TimerExpired);
+            match timer.on_expiry {
+                TimerExpiry::Call => Some(self.reveal(game_state, events)),
+                TimerExpiry::Flag => Some(None),
+            }
+        });
+        match expired {
+            Ok(Some(revealed)) => {
+                log::debug!("Round timer ran out in room {}", self.room_id);
+                self.notify_subscribers();
+                if let Some(revealed) = revealed {
+                    self.notify_consensus(revealed);
+                }
+            }
+            Ok(None) => (),
+            Err(e) => log::error!("Failed to expire timer for room {}: {}", self.room_id, e),
+        }
+    }
+
+    /// Drops players past the disconnect timeout.
+    ///
+    /// `idle` holds the players that were idle as of the last sweep. Returns
+    /// whether this sweep changed anything subscribers can see.
+    fn reap(&self, idle: &mut HashSet<PlayerId>) -> Result<bool, StorageError> {
+        let mut changed = false;
+
+        // Most sweeps have nothing to remove, so only write when there is.
+        let is_expired = |player: &Player| {
+            player.silence() >= self.disconnect_timeout && !self.is_connected(player.id)
+        };
+        let (expired, players) = self.read(|game_state| {
+            let expired = game_state.players.values().any(is_expired);
+            (expired, game_state.players.clone())
+        })?;
+        let players = if expired {
+            let (removed, players) = self.update_and_emit(|game_state, events| {
+                let removed: Vec<PlayerId> = game_state
+                    .players
+                    .values()
+                    .filter(|player| is_expired(player))
+                    .map(|player| player.id)
+                    .collect();
+                let mut closed = self.closed.lock().unwrap();
+                for player_id in &removed {
+                    game_state.players.remove(player_id);
+                    closed.remove(player_id);
+                    events.push(GameEvent::PlayerLeft(*player_id));
+                }
+                (removed.len(), game_state.players.clone())
+            })?;
+            if removed > 0 {
+                log::info!(
+                    "Removed {} idle players from room {}",
+                    removed,
+                    self.room_id
+                );
+                changed = true;
+            }
+            players
+        } else {
+            players
+        };
+
+        let now_idle: HashSet<PlayerId> = players
+            .values()
+            .filter(|player| self.is_idle(player))
+            .map(|player| player.id)
+            .collect();
+        // Sockets closing and players coming back are announced as they
+        // happen, which leaves the players who went quiet.
+        let went_quiet: Vec<PlayerId> = {
+            let closed = self.closed.lock().unwrap();
+            now_idle
+                .difference(idle)
+                .filter(|player_id| !closed.contains(player_id))
+                .copied()
+                .collect()
+        };
+        if !went_quiet.is_empty() {
+            self.update_and_emit(|_, events| {
+                events.extend(
+                    went_quiet
+                        .iter()
+                        .map(|player_id| GameEvent::PlayerIdleChanged(*player_id, true)),
+                )
+            })?;
+            changed = true;
+        }
+        *idle = now_idle;
+        Ok(changed)
+    }
+
+    /// The deck in play for the given `GameState`.
+    pub fn deck(&self, game_state: &GameState) -> Arc<Deck> {
+        game_state
+            .deck
+            .as_deref()
+            .and_then(|name| self.decks.get(name).ok())
+            .unwrap_or_else(|| self.default_deck.clone())
+    }
+
+    /// Looks at the `GameState` without copying it. Readers that need more
+    /// than a few fields can clone what they need.
+    pub fn read<R>(&self, f: impl FnOnce(&GameState) -> R) -> Result<R, StorageError> {
+        let mut f = Some(f);
+        let mut outcome = None;
+        self.storage
+            .read_game_state(self.room_id, &mut |game_state| {
+                if let Some(f) = f.take() {
+                    outcome = Some(f(game_state));
+                }
+            })?;
+        Ok(outcome.expect("storage read the game state"))
+    }
+
+    /// Applies `f` to the `GameState` and saves the outcome.
+    ///
+    /// Subscribers are *not* notified, since not every update is worth
+    /// pushing.
+    pub fn update<R>(&self, f: impl FnOnce(&mut GameState) -> R) -> Result<R, StorageError> {
+        self.update_and_emit(|game_state, _| f(game_state))
+    }
+
+    /// Like `update`, then sends the events `f` pushed to `gameEvents`
+    /// subscribers.
+    ///
+    /// The events are numbered and sent before any other update to the room
+    /// can start, so they go out in the same order as the changes they
+    /// describe. Nothing is sent when saving fails.
+    pub fn update_and_emit<R>(
+        &self,
+        f: impl FnOnce(&mut GameState, &mut Vec<GameEvent>) -> R,
+    ) -> Result<R, StorageError> {
+        let mut sequence = self.sequence.lock().unwrap();
+        let mut f = Some(f);
+        let mut outcome = None;
+        let mut events = Vec::new();
+        let mut timer = None;
+        self.storage
+            .update_game_state(self.room_id, &mut |game_state: &mut GameState| {
+                if let Some(f) = f.take() {
+                    outcome = Some(f(game_state, &mut events));
+                    timer = game_state.timer.as_ref().map(|timer| timer.started_at);
+                }
+            })?;
+        self.stop_stale_timer(timer);
+        for event in events {
+            *sequence += 1;
+            // Nobody listening is fine.
+            let _ = self.event_notifier.send((*sequence, event));
+        }
+        Ok(outcome.expect("storage applied the update"))
+    }
+
+    /// Stops counting down a timer that calls, resets and cancellations have
+    /// taken out of the game state.
+    fn stop_stale_timer(&self, current: Option<SystemTime>) {
+        let mut timer_task = self.timer_task.lock().unwrap();
+        let stale = matches!(&*timer_task, Some((started_at, _)) if Some(*started_at) != current);
+        if stale {
+            if let Some((_, handle)) = timer_task.take() {
+                handle.abort();
+            }
+        }
+    }
+
+    /// Freezes and reveals the card selections.
+    ///
+    /// When the table reached consensus, consensus subscribers hear about it.
+    pub fn call(&self) -> Result<(), StorageError> {
+        let revealed =
+            self.update_and_emit(|game_state, events| self.reveal(game_state, events))?;
+        self.notify_subscribers();
+        if let Some(revealed) = revealed {
+            self.notify_consensus(revealed);
+        }
+        Ok(())
+    }
+
+    /// Freezes the table, unless it already is, and returns the results.
+    fn reveal(
+        &self,
+        game_state: &mut GameState,
+        events: &mut Vec<GameEvent>,
+    ) -> Option<(Arc<Deck>, Results)> {
+        if game_state.is_calling {
+            return None;
+        }
+        game_state.is_calling = true;
+        game_state.called_at = Some(SystemTime::now());
+        // The round is over, so is its countdown.
+        if game_state.timer.take().is_some_and(|timer| !timer.expired) {
+            events.push(GameEvent::TimerCancelled);
+        }
+        let deck = self.deck(game_state);
+        let results = Results::compute(&deck, game_state);
+        events.push(GameEvent::Called(deck.clone(), results.clone()));
+        Some((deck, results))
+    }
+
+    fn notify_consensus(&self, (deck, results): (Arc<Deck>, Results)) {
+        if results.consensus.reached {
+            log::debug!("Consensus reached in room {}", self.room_id);
+            // Nobody listening is fine.
+            let _ = self.consensus_notifier.send((deck, results));
+        }
+    }
+
+    /// Cancels any pending automatic call, then checks whether a new one is
+    /// due.
+    pub fn vote_changed(self: &Arc<Self>) {
+        self.vote_generation.fetch_add(1, Ordering::SeqCst);
+        self.maybe_auto_call();
+    }
+
+    /// Calls the cards, or schedules the call, when the room has auto call
+    /// turned on and every active player has voted.
+    pub fn maybe_auto_call(self: &Arc<Self>) {
+        let delay = match self.auto_call_due() {
+            Some(delay) => delay,
+            None => return,
+        };
+        if delay == Duration::ZERO {
+            self.auto_call();
+            return;
+        }
+        let generation = self.vote_generation.load(Ordering::SeqCst);
+        let session = Arc::downgrade(self);
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if let Some(session) = session.upgrade() {
+                if session.vote_generation.load(Ordering::SeqCst) == generation
+                    && session.auto_call_due().is_some()
+                {
+                    session.auto_call();
+                }
+            }
+        });
+    }
+
+    /// The delay before calling, if a call is due.
+    fn auto_call_due(&self) -> Option<Duration> {
+        match self.read(|game_state| self.auto_call_delay(game_state)) {
+            Ok(delay) => delay,
+            Err(e) => {
+                log::error!(
+                    "Failed to check for auto call in room {}: {}",
+                    self.room_id,
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    fn auto_call_delay(&self, game_state: &GameState) -> Option<Duration> {
+        let auto_call = game_state.settings.auto_call?;
+        if game_state.is_calling {
+            return None;
+        }
+        // Idle players would hold everyone up forever.
+        let mut active = game_state
+            .players
+            .values()
+            .filter(|player| player.role.votes() && !self.is_idle(player))
+            .peekable();
+        active.peek()?;
+        if active.all(|player| player.selected_card.is_some()) {
+            Some(Duration::from_millis(auto_call.delay_ms))
+        } else {
+            None
+        }
+    }
+
+    fn auto_call(&self) {
+        log::debug!("Everybody voted, calling room {}", self.room_id);
+        if let Err(e) = self.call() {
+            log::error!("Failed to auto call room {}: {}", self.room_id, e);
+        }
+    }
+
+    /// Sends an event to `gameEvents` subscribers, numbered one after the
+    /// last. Events that go with a change to the game state are sent by
+    /// `update_and_emit` instead.
+    pub fn emit(&self, event: GameEvent) {
+        let mut sequence = self.sequence.lock().unwrap();
+        *sequence += 1;
+        // Nobody listening is fine.
+        let _ = self.event_notifier.send((*sequence, event));
+    }
+
     /// Pushes the current `GameState` to all active subscriptions.
     pub fn notify_subscribers(&self) {
         if let Err(err) = self.game_state_notifier.send(()) {
@@ -102,3 +1096,284 @@ impl PlaySession {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{MemoryStorage, RoomRecord};
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    fn session(disconnect_timeout: Duration) -> Arc<PlaySession> {
+        let storage = Arc::new(MemoryStorage::default());
+        let record = RoomRecord {
+            id: RoomId::new_v4(),
+            slug: "test".to_string(),
+            name: "test".to_string(),
+            admin_key: "key".to_string(),
+        };
+        storage.create_room(&record).unwrap();
+        let decks = Decks::default();
+        let default_deck = decks.get("fib").unwrap();
+        Arc::new(PlaySession::new(
+            record.id,
+            record.admin_key,
+            Arc::new(decks),
+            default_deck,
+            disconnect_timeout,
+            storage,
+        ))
+    }
+
+    fn join(session: &PlaySession, name: &str) -> PlayerId {
+        let player = Player::new(name.to_string(), PlayerId::new_v4(), Role::Voter);
+        let id = player.id;
+        session
+            .update(|game_state| game_state.players.insert(id, player))
+            .unwrap();
+        id
+    }
+
+    /// Lets spawned tasks catch up, without moving the paused clock.
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Everything sent since the last drain.
+    fn drain<T: Clone>(rx: &mut broadcast::Receiver<T>) -> Vec<T> {
+        let mut items = Vec::new();
+        loop {
+            match rx.try_recv() {
+                Ok(item) => items.push(item),
+                Err(TryRecvError::Empty) => return items,
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_reaper_removes_players_past_the_timeout() {
+        let session = session(Duration::from_secs(60));
+        let gone = join(&session, "Gone");
+        let away = join(&session, "Away");
+        let here = join(&session, "Here");
+        session
+            .update(|game_state| {
+                let long_ago = SystemTime::now() - Duration::from_secs(120);
+                let a_while_ago = SystemTime::now() - Duration::from_secs(45);
+                game_state.players.get_mut(&gone).unwrap().last_heartbeat = long_ago;
+                game_state.players.get_mut(&away).unwrap().last_heartbeat = a_while_ago;
+            })
+            .unwrap();
+        let mut notifications = session.game_state_notifier.subscribe();
+        let mut events = session.event_notifier.subscribe();
+
+        // The first sweep runs straight away.
+        session.start_reaper();
+        settle().await;
+        let game_state = session.read(GameState::clone).unwrap();
+        assert!(!game_state.players.contains_key(&gone));
+        assert!(session.is_idle(&game_state.players[&away]));
+        assert!(!session.is_idle(&game_state.players[&here]));
+        assert_eq!(drain(&mut notifications).len(), 1);
+        let sent = drain(&mut events);
+        assert!(matches!(
+            sent[..],
+            [(1, GameEvent::PlayerLeft(left)), (2, GameEvent::PlayerIdleChanged(quiet, true))]
+                if left == gone && quiet == away
+        ));
+
+        // Sweeps that find nothing new stay quiet.
+        tokio::time::sleep(REAPER_INTERVAL * 3).await;
+        assert!(drain(&mut notifications).is_empty());
+        assert!(drain(&mut events).is_empty());
+
+        // Coming back is worth telling everyone about, straight away.
+        assert!(session.heartbeat(away).unwrap());
+        assert_eq!(drain(&mut notifications).len(), 1);
+        let sent = drain(&mut events);
+        assert!(matches!(sent[..], [(3, GameEvent::PlayerIdleChanged(id, false))] if id == away));
+        tokio::time::sleep(REAPER_INTERVAL).await;
+        assert!(drain(&mut notifications).is_empty());
+
+        // Going quiet is noticed by the next sweep.
+        session
+            .update(|game_state| {
+                let a_while_ago = SystemTime::now() - Duration::from_secs(45);
+                game_state.players.get_mut(&here).unwrap().last_heartbeat = a_while_ago;
+            })
+            .unwrap();
+        tokio::time::sleep(REAPER_INTERVAL).await;
+        let sent = drain(&mut events);
+        assert!(matches!(sent[..], [(4, GameEvent::PlayerIdleChanged(id, true))] if id == here));
+
+        session.stop_tasks();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sockets_opening_and_closing_are_presence_events() {
+        let session = session(Duration::from_secs(60));
+        let alice = join(&session, "Alice");
+        let mut events = session.event_notifier.subscribe();
+
+        let first = session.connected(alice);
+        let second = session.connected(alice);
+        // Joining just now counts as present already.
+        assert!(drain(&mut events).is_empty());
+
+        drop(first);
+        assert!(drain(&mut events).is_empty());
+        drop(second);
+        let events_sent = drain(&mut events);
+        assert!(matches!(
+            events_sent[..],
+            [(1, GameEvent::PlayerIdleChanged(id, true))] if id == alice
+        ));
+
+        let back = session.connected(alice);
+        let events_sent = drain(&mut events);
+        assert!(matches!(
+            events_sent[..],
+            [(2, GameEvent::PlayerIdleChanged(id, false))] if id == alice
+        ));
+        drop(back);
+    }
+
+    fn vote(session: &Arc<PlaySession>, player_id: PlayerId, card: Option<usize>) {
+        session
+            .update(|game_state| {
+                game_state
+                    .players
+                    .get_mut(&player_id)
+                    .unwrap()
+                    .selected_card = card
+            })
+            .unwrap();
+        session.vote_changed();
+    }
+
+    fn is_calling(session: &PlaySession) -> bool {
+        session.read(GameState::clone).unwrap().is_calling
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_changed_vote_cancels_the_pending_auto_call() {
+        let session = session(Duration::from_secs(60));
+        let delay = Duration::from_secs(3);
+        session
+            .update(|game_state| {
+                game_state.settings.auto_call = Some(AutoCall {
+                    delay_ms: delay.as_millis() as u64,
+                })
+            })
+            .unwrap();
+        let alice = join(&session, "Alice");
+        let bob = join(&session, "Bob");
+
+        vote(&session, alice, Some(1));
+        vote(&session, bob, Some(2));
+        tokio::time::sleep(delay / 2).await;
+        // Taking a card back means not everybody has voted any more.
+        vote(&session, alice, None);
+        tokio::time::sleep(delay * 2).await;
+        assert!(!is_calling(&session));
+
+        vote(&session, alice, Some(1));
+        tokio::time::sleep(delay / 2).await;
+        // Changing a card starts the wait over.
+        vote(&session, bob, Some(3));
+        tokio::time::sleep(delay * 3 / 4).await;
+        assert!(!is_calling(&session));
+        tokio::time::sleep(delay / 2).await;
+        assert!(is_calling(&session));
+    }
+
+    /// Longer than any of the timers below takes to run out.
+    const TIMER_RUNS_OUT: Duration = Duration::from_secs(600);
+
+    fn names(events: Vec<(u64, GameEvent)>) -> Vec<String> {
+        events
+            .into_iter()
+            .map(|(_, event)| match event {
+                GameEvent::TimerWarning(left) => format!("TimerWarning({})", left.as_secs()),
+                event => format!("{:?}", event)
+                    .split(|c: char| !c.is_alphanumeric())
+                    .next()
+                    .unwrap()
+                    .to_string(),
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_timer_warns_then_flags_the_round() {
+        let session = session(Duration::from_secs(60));
+        let mut events = session.event_notifier.subscribe();
+        session
+            .start_timer(Duration::from_secs(90), TimerExpiry::Flag)
+            .unwrap();
+        tokio::time::sleep(TIMER_RUNS_OUT).await;
+        assert_eq!(
+            names(drain(&mut events)),
+            [
+                "TimerStarted",
+                "TimerWarning(60)",
+                "TimerWarning(30)",
+                "TimerWarning(10)",
+                "TimerExpired",
+            ]
+        );
+        let game_state = session.read(GameState::clone).unwrap();
+        assert!(game_state.timer.unwrap().expired);
+        assert!(!game_state.is_calling);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_timer_calls_the_round() {
+        let session = session(Duration::from_secs(60));
+        let mut events = session.event_notifier.subscribe();
+        session
+            .start_timer(Duration::from_secs(5), TimerExpiry::Call)
+            .unwrap();
+        tokio::time::sleep(TIMER_RUNS_OUT).await;
+        assert_eq!(
+            names(drain(&mut events)),
+            ["TimerStarted", "TimerExpired", "Called"]
+        );
+        assert!(is_calling(&session));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn calling_or_resetting_stops_the_timer() {
+        let session = session(Duration::from_secs(60));
+        let deck = session.default_deck.clone();
+        let mut events = session.event_notifier.subscribe();
+
+        session
+            .start_timer(Duration::from_secs(90), TimerExpiry::Call)
+            .unwrap();
+        session.call().unwrap();
+        assert!(session.timer_task.lock().unwrap().is_none());
+        tokio::time::sleep(TIMER_RUNS_OUT).await;
+        assert_eq!(
+            names(drain(&mut events)),
+            ["TimerStarted", "TimerCancelled", "Called"]
+        );
+        assert!(session.read(GameState::clone).unwrap().timer.is_none());
+
+        session
+            .update(|game_state| game_state.reset_round(&deck))
+            .unwrap();
+        session
+            .start_timer(Duration::from_secs(90), TimerExpiry::Flag)
+            .unwrap();
+        session
+            .update(|game_state| game_state.reset_round(&deck))
+            .unwrap();
+        assert!(session.timer_task.lock().unwrap().is_none());
+        tokio::time::sleep(TIMER_RUNS_OUT).await;
+        assert_eq!(names(drain(&mut events)), ["TimerStarted"]);
+        assert!(session.read(GameState::clone).unwrap().timer.is_none());
+    }
+}
@@ -0,0 +1,288 @@
+use crate::deck::{Deck, DeckError, Decks};
+use crate::poker::{AdminKey, PlaySession};
+use crate::storage::{AuditEntry, RoomRecord, Storage, StorageError};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// Stable handle for identifying rooms.
+pub type RoomId = Uuid;
+
+/// The slug used for the room created at startup, so a bare instance still
+/// behaves like a single planning table.
+pub const DEFAULT_ROOM_SLUG: &str = "default";
+
+const MAX_SLUG_LEN: usize = 64;
+
+#[derive(Debug)]
+pub enum RoomError {
+    InvalidSlug(String),
+    SlugTaken(String),
+    TooManyRooms(usize),
+    DefaultRoom,
+    Deck(DeckError),
+    Storage(StorageError),
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomError::InvalidSlug(slug) => write!(
+                f,
+                "Invalid room slug: `{}`. Use up to {} lowercase letters, digits or dashes.",
+                slug, MAX_SLUG_LEN
+            ),
+            RoomError::SlugTaken(slug) => write!(f, "Room slug already in use: `{}`", slug),
+            RoomError::TooManyRooms(max) => write!(
+                f,
+                "The server already has {} rooms. Delete one to make room for another.",
+                max
+            ),
+            RoomError::DefaultRoom => {
+                write!(f, "The `{}` room can't be deleted.", DEFAULT_ROOM_SLUG)
+            }
+            RoomError::Deck(e) => write!(f, "{}", e),
+            RoomError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for RoomError {}
+
+impl From<DeckError> for RoomError {
+    fn from(e: DeckError) -> Self {
+        RoomError::Deck(e)
+    }
+}
+
+impl From<StorageError> for RoomError {
+    fn from(e: StorageError) -> Self {
+        RoomError::Storage(e)
+    }
+}
+
+/// A single planning table.
+pub struct Room {
+    pub id: RoomId,
+    /// Human friendly handle used in urls.
+    pub slug: String,
+    pub name: String,
+    pub session: Arc<PlaySession>,
+}
+
+/// Holds every room on the server.
+///
+/// Rooms are looked up by either their id or their slug.
+pub struct RoomRegistry {
+    rooms: RwLock<HashMap<RoomId, Arc<Room>>>,
+    decks: Arc<Decks>,
+    default_deck: Arc<Deck>,
+    disconnect_timeout: Duration,
+    storage: Arc<dyn Storage>,
+    /// No more rooms than this can be created. Each one has a reaper task
+    /// running for as long as it exists.
+    max_rooms: usize,
+}
+
+fn is_valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= MAX_SLUG_LEN
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+impl RoomRegistry {
+    pub fn new(
+        decks: Arc<Decks>,
+        default_deck: Arc<Deck>,
+        disconnect_timeout: Duration,
+        storage: Arc<dyn Storage>,
+        max_rooms: usize,
+    ) -> RoomRegistry {
+        RoomRegistry {
+            rooms: Default::default(),
+            decks,
+            default_deck,
+            disconnect_timeout,
+            storage,
+            max_rooms,
+        }
+    }
+
+    /// Sets up the session for a room. Its background tasks are left for the
+    /// caller to start once the game state is ready for them.
+    fn build_room(&self, record: RoomRecord) -> Arc<Room> {
+        Arc::new(Room {
+            id: record.id,
+            slug: record.slug,
+            name: record.name,
+            session: Arc::new(PlaySession::new(
+                record.id,
+                record.admin_key,
+                self.decks.clone(),
+                self.default_deck.clone(),
+                self.disconnect_timeout,
+                self.storage.clone(),
+            )),
+        })
+    }
+
+    fn start_tasks(room: &Room) {
+        room.session.start_reaper();
+        room.session.resume_timer();
+    }
+
+    /// Brings back the rooms that were saved by the storage backend.
+    ///
+    /// Heartbeats are rebased to the time of the restore, otherwise everyone
+    /// would be reaped on the first heartbeat after a long-ish restart.
+    ///
+    /// An admin key given for the default room wins over the saved one.
+    pub fn restore(&self, default_admin_key: Option<&AdminKey>) -> Result<(), StorageError> {
+        let now = SystemTime::now();
+        let mut rooms = self.rooms.write().unwrap();
+        for mut record in self.storage.load_rooms()? {
+            if record.slug == DEFAULT_ROOM_SLUG {
+                if let Some(admin_key) = default_admin_key {
+                    record.admin_key = admin_key.clone();
+                }
+            }
+            let room = self.build_room(record);
+            let players = room.session.update(|game_state| {
+                for player in game_state.players.values_mut() {
+                    player.last_heartbeat = now;
+                }
+                game_state.players.len()
+            })?;
+            // Only now, or the reaper could see the stale heartbeats.
+            Self::start_tasks(&room);
+            log::info!(
+                "Restored room: id={} slug={} players={}",
+                room.id,
+                room.slug,
+                players
+            );
+            rooms.insert(room.id, room);
+        }
+        Ok(())
+    }
+
+    /// Creates a new room with its own game state and admin key.
+    ///
+    /// When `slug` is not given, one is derived from the room id. When
+    /// `admin_key` is not given, a random one is generated. When `deck` is not
+    /// given, the server default is used.
+    pub fn create(
+        &self,
+        slug: Option<String>,
+        name: Option<String>,
+        admin_key: Option<AdminKey>,
+        deck: Option<String>,
+    ) -> Result<Arc<Room>, RoomError> {
+        let id = RoomId::new_v4();
+        let slug = slug.unwrap_or_else(|| id.to_simple().to_string()[..8].to_string());
+        if !is_valid_slug(&slug) || slug.parse::<RoomId>().is_ok() {
+            return Err(RoomError::InvalidSlug(slug));
+        }
+
+        if let Some(deck) = &deck {
+            self.decks.get(deck)?;
+        }
+
+        let mut rooms = self.rooms.write().unwrap();
+        if rooms.values().any(|room| room.slug == slug) {
+            return Err(RoomError::SlugTaken(slug));
+        }
+        if rooms.len() >= self.max_rooms {
+            return Err(RoomError::TooManyRooms(self.max_rooms));
+        }
+
+        let record = RoomRecord {
+            id,
+            name: name.unwrap_or_else(|| slug.clone()),
+            slug,
+            admin_key: admin_key.unwrap_or_else(|| Uuid::new_v4().to_string()),
+        };
+        self.storage.create_room(&record)?;
+        let room = self.build_room(record);
+        if deck.is_some() {
+            room.session.update(|game_state| game_state.deck = deck)?;
+        }
+        Self::start_tasks(&room);
+        log::info!("Created room: id={} slug={}", room.id, room.slug);
+        rooms.insert(id, room.clone());
+        Ok(room)
+    }
+
+    /// Deletes a room along with its game state, and stops its background
+    /// tasks. Returns the room, if there was one.
+    ///
+    /// The default room stays, since a bare instance is just that one room.
+    pub fn remove(&self, key: &str) -> Result<Option<Arc<Room>>, RoomError> {
+        let room = match self.get(key) {
+            Some(room) => room,
+            None => return Ok(None),
+        };
+        if room.slug == DEFAULT_ROOM_SLUG {
+            return Err(RoomError::DefaultRoom);
+        }
+        let mut rooms = self.rooms.write().unwrap();
+        if rooms.remove(&room.id).is_none() {
+            // Somebody else got there first.
+            return Ok(None);
+        }
+        self.storage.delete_room(room.id)?;
+        room.session.stop_tasks();
+        log::info!("Deleted room: id={} slug={}", room.id, room.slug);
+        Ok(Some(room))
+    }
+
+    pub fn decks(&self) -> &Decks {
+        &self.decks
+    }
+
+    /// Look up a room by id or slug.
+    pub fn get(&self, key: &str) -> Option<Arc<Room>> {
+        let rooms = self.rooms.read().unwrap();
+        match key.parse::<RoomId>() {
+            Ok(id) => rooms.get(&id).cloned(),
+            Err(_) => rooms.values().find(|room| room.slug == key).cloned(),
+        }
+    }
+
+    pub fn list(&self) -> Vec<Arc<Room>> {
+        let mut rooms: Vec<_> = self.rooms.read().unwrap().values().cloned().collect();
+        rooms.sort_by(|a, b| a.slug.cmp(&b.slug));
+        rooms
+    }
+
+    /// Stops the background tasks for every room.
+    pub fn shutdown(&self) {
+        for room in self.rooms.read().unwrap().values() {
+            room.session.stop_tasks();
+        }
+    }
+
+    /// Records a privileged action. Failing to is logged rather than failing
+    /// the action.
+    pub fn audit(&self, entry: AuditEntry) {
+        log::info!(
+            "Audit: {} ({}) ran {} in room {:?}",
+            entry.actor,
+            entry.player_id,
+            entry.action,
+            entry.room_id
+        );
+        if let Err(e) = self.storage.append_audit(&entry) {
+            log::error!("Failed to record audit entry: {}", e);
+        }
+    }
+
+    /// The most recent privileged actions, newest first.
+    pub fn audit_log(&self, limit: usize) -> Result<Vec<AuditEntry>, StorageError> {
+        self.storage.audit_log(limit)
+    }
+}
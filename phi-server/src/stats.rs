@@ -0,0 +1,375 @@
+//! Number crunching for when the cards are revealed.
+
+use crate::deck::{CardKind, Deck};
+use crate::poker::{ConsensusRule, GameState};
+
+/// How many players picked a given card.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CardCount {
+    /// Index into the cards of the deck.
+    pub card: usize,
+    pub count: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Consensus {
+    pub rule: ConsensusRule,
+    pub reached: bool,
+    /// The card to go with. When there's no consensus this is a best guess
+    /// based on the mean.
+    pub suggested_card: Option<usize>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Results {
+    /// How many voting players have a card selected, of any kind.
+    pub vote_count: usize,
+    /// Counts for every card that was picked at least once, in deck order.
+    pub distribution: Vec<CardCount>,
+    pub mean: Option<f64>,
+    pub median: Option<f64>,
+    /// The most picked numeric cards. More than one when there's a tie.
+    pub mode: Vec<usize>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    /// Population standard deviation of the numeric votes.
+    pub standard_deviation: Option<f64>,
+    /// The numeric card closest to the mean.
+    pub nearest_card: Option<usize>,
+    pub unknown_count: usize,
+    pub infinite_count: usize,
+    pub break_count: usize,
+    pub consensus: Consensus,
+}
+
+impl Results {
+    pub fn compute(deck: &Deck, game_state: &GameState) -> Results {
+        let mut counts = vec![0; deck.cards.len()];
+        for card in game_state
+            .players
+            .values()
+            .filter(|player| player.role.votes())
+            .filter_map(|player| player.selected_card)
+        {
+            if let Some(count) = counts.get_mut(card) {
+                *count += 1;
+            }
+        }
+
+        let mut results = Results::default();
+        let mut values = vec![];
+        for (idx, &count) in counts.iter().enumerate().filter(|(_, n)| **n > 0) {
+            let card = &deck.cards[idx];
+            results.vote_count += count;
+            results.distribution.push(CardCount { card: idx, count });
+            match (card.kind, card.value) {
+                (CardKind::Numeric, Some(value)) => {
+                    values.extend(std::iter::repeat_n(value, count))
+                }
+                (CardKind::Numeric, None) => (),
+                (CardKind::Unknown, _) => results.unknown_count += count,
+                (CardKind::Infinite, _) => results.infinite_count += count,
+                (CardKind::Break, _) => results.break_count += count,
+            }
+        }
+
+        if values.is_empty() {
+            results.consensus = Consensus::evaluate(deck, game_state, &results);
+            return results;
+        }
+
+        values.sort_by(|a, b| a.partial_cmp(b).expect("card values are finite"));
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+        let mid = values.len() / 2;
+        let median = if values.len() % 2 == 0 {
+            (values[mid - 1] + values[mid]) / 2.0
+        } else {
+            values[mid]
+        };
+
+        let numeric_counts = results.distribution.iter().filter(|c| {
+            let card = &deck.cards[c.card];
+            card.kind == CardKind::Numeric && card.value.is_some()
+        });
+        let top = numeric_counts.clone().map(|c| c.count).max().unwrap_or(0);
+        results.mode = numeric_counts
+            .filter(|c| c.count == top)
+            .map(|c| c.card)
+            .collect();
+
+        // (index, distance, value) of the closest card so far.
+        let mut nearest: Option<(usize, f64, f64)> = None;
+        for (idx, card) in deck.cards.iter().enumerate() {
+            let value = match (card.kind, card.value) {
+                (CardKind::Numeric, Some(value)) => value,
+                _ => continue,
+            };
+            let distance = (value - mean).abs();
+            let closer = match nearest {
+                None => true,
+                // Ties go to the bigger card, since it's the safer estimate.
+                Some((_, best_distance, best_value)) => {
+                    distance < best_distance || (distance == best_distance && value > best_value)
+                }
+            };
+            if closer {
+                nearest = Some((idx, distance, value));
+            }
+        }
+        results.nearest_card = nearest.map(|(idx, _, _)| idx);
+
+        results.mean = Some(mean);
+        results.median = Some(median);
+        results.min = values.first().copied();
+        results.max = values.last().copied();
+        results.standard_deviation = Some(variance.sqrt());
+        results.consensus = Consensus::evaluate(deck, game_state, &results);
+        results
+    }
+}
+
+impl Consensus {
+    fn evaluate(deck: &Deck, game_state: &GameState, results: &Results) -> Consensus {
+        let rule = game_state.settings.consensus_rule;
+        // Players taking a break are sitting the round out, but "?" and "∞"
+        // are opinions that count against agreement. They can never be agreed
+        // on though, since they aren't an estimate.
+        let votes: Vec<_> = results
+            .distribution
+            .iter()
+            .filter(|c| deck.cards[c.card].kind != CardKind::Break)
+            .collect();
+        let total: usize = votes.iter().map(|c| c.count).sum();
+        let top = votes.iter().max_by_key(|c| c.count);
+
+        let winner = match (rule, top) {
+            (_, None) => None,
+            (ConsensusRule::Unanimous, Some(top)) if votes.len() == 1 => Some(top.card),
+            (ConsensusRule::Supermajority { percent }, Some(top))
+                if top.count * 100 >= total * percent as usize =>
+            {
+                Some(top.card)
+            }
+            (ConsensusRule::Adjacent, Some(_)) if is_adjacent(deck, &votes) => {
+                if votes.len() == 1 {
+                    votes.first().map(|c| c.card)
+                } else {
+                    results.nearest_card
+                }
+            }
+            _ => None,
+        }
+        .filter(|&card| is_numeric(deck, card));
+
+        Consensus {
+            rule,
+            reached: winner.is_some(),
+            suggested_card: winner.or(results.nearest_card),
+        }
+    }
+}
+
+fn is_numeric(deck: &Deck, card: usize) -> bool {
+    let card = &deck.cards[card];
+    card.kind == CardKind::Numeric && card.value.is_some()
+}
+
+/// Whether every vote is numeric and they all fall on one or two neighbouring
+/// cards, ordered by value.
+fn is_adjacent(deck: &Deck, votes: &[&CardCount]) -> bool {
+    let mut ranked: Vec<(usize, f64)> = deck
+        .cards
+        .iter()
+        .enumerate()
+        .filter(|(_, card)| card.kind == CardKind::Numeric)
+        .filter_map(|(idx, card)| card.value.map(|value| (idx, value)))
+        .collect();
+    ranked.sort_by(|a, b| a.1.partial_cmp(&b.1).expect("card values are finite"));
+
+    let mut ranks = vec![];
+    for vote in votes {
+        match ranked.iter().position(|(idx, _)| *idx == vote.card) {
+            Some(rank) => ranks.push(rank),
+            None => return false,
+        }
+    }
+    match (ranks.iter().min(), ranks.iter().max()) {
+        (Some(min), Some(max)) => max - min <= 1,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deck::Decks;
+    use crate::poker::{Player, Role};
+    use uuid::Uuid;
+
+    fn fib() -> std::sync::Arc<Deck> {
+        Decks::default().get("fib").unwrap()
+    }
+
+    /// A room where each player has picked the card with the given label.
+    fn game_state(deck: &Deck, labels: &[&str]) -> GameState {
+        let mut game_state = GameState::default();
+        for label in labels {
+            let id = Uuid::new_v4();
+            let mut player = Player::new(label.to_string(), id, Role::Voter);
+            player.selected_card = deck.cards.iter().position(|card| card.label == *label);
+            game_state.players.insert(id, player);
+        }
+        game_state
+    }
+
+    fn label(deck: &Deck, card: Option<usize>) -> Option<&str> {
+        card.map(|card| deck.cards[card].label.as_str())
+    }
+
+    #[test]
+    fn no_votes() {
+        let deck = fib();
+        let results = Results::compute(&deck, &game_state(&deck, &[]));
+        assert_eq!(results.vote_count, 0);
+        assert!(results.distribution.is_empty());
+        assert_eq!(results.mean, None);
+        assert_eq!(results.median, None);
+        assert_eq!(results.nearest_card, None);
+    }
+
+    #[test]
+    fn numeric_stats() {
+        let deck = fib();
+        let results = Results::compute(&deck, &game_state(&deck, &["1", "2", "3", "8"]));
+        assert_eq!(results.vote_count, 4);
+        assert_eq!(results.mean, Some(3.5));
+        assert_eq!(results.median, Some(2.5));
+        assert_eq!(results.min, Some(1.0));
+        assert_eq!(results.max, Some(8.0));
+        assert_eq!(results.standard_deviation, Some(7.25f64.sqrt()));
+        assert_eq!(label(&deck, results.nearest_card), Some("3"));
+    }
+
+    #[test]
+    fn nearest_card_ties_go_up() {
+        let deck = fib();
+        let results = Results::compute(&deck, &game_state(&deck, &["3", "5"]));
+        assert_eq!(results.mean, Some(4.0));
+        assert_eq!(label(&deck, results.nearest_card), Some("5"));
+    }
+
+    #[test]
+    fn odd_median() {
+        let deck = fib();
+        let results = Results::compute(&deck, &game_state(&deck, &["13", "1", "3"]));
+        assert_eq!(results.median, Some(3.0));
+    }
+
+    #[test]
+    fn distribution_and_special_cards() {
+        let deck = fib();
+        let state = game_state(&deck, &["5", "?", "5", "☕", "∞", "3"]);
+        let results = Results::compute(&deck, &state);
+        assert_eq!(results.vote_count, 6);
+        let distribution: Vec<_> = results
+            .distribution
+            .iter()
+            .map(|c| (deck.cards[c.card].label.as_str(), c.count))
+            .collect();
+        assert_eq!(
+            distribution,
+            [("3", 1), ("5", 2), ("∞", 1), ("?", 1), ("☕", 1)]
+        );
+        assert_eq!(results.unknown_count, 1);
+        assert_eq!(results.infinite_count, 1);
+        assert_eq!(results.break_count, 1);
+        // Only the numeric cards go into the numbers.
+        assert_eq!(results.mean, Some(13.0 / 3.0));
+        assert_eq!(results.mode.len(), 1);
+        assert_eq!(label(&deck, results.mode.first().copied()), Some("5"));
+    }
+
+    #[test]
+    fn spectators_dont_count() {
+        let deck = fib();
+        let mut state = game_state(&deck, &["1", "13"]);
+        for player in state.players.values_mut() {
+            if player.name == "13" {
+                player.role = Role::Spectator;
+            }
+        }
+        let results = Results::compute(&deck, &state);
+        assert_eq!(results.vote_count, 1);
+        assert_eq!(results.mean, Some(1.0));
+    }
+
+    #[test]
+    fn tied_mode() {
+        let deck = fib();
+        let results = Results::compute(&deck, &game_state(&deck, &["2", "8", "2", "8", "1"]));
+        let mode: Vec<_> = results
+            .mode
+            .iter()
+            .map(|&c| label(&deck, Some(c)))
+            .collect();
+        assert_eq!(mode, [Some("2"), Some("8")]);
+    }
+
+    fn consensus(rule: ConsensusRule, labels: &[&str]) -> (bool, Option<String>) {
+        let deck = fib();
+        let mut state = game_state(&deck, labels);
+        state.settings.consensus_rule = rule;
+        let consensus = Results::compute(&deck, &state).consensus;
+        let suggested = label(&deck, consensus.suggested_card).map(str::to_string);
+        (consensus.reached, suggested)
+    }
+
+    #[test]
+    fn unanimous() {
+        let rule = ConsensusRule::Unanimous;
+        assert_eq!(consensus(rule, &["5", "5"]), (true, Some("5".into())));
+        assert_eq!(consensus(rule, &["5", "5", "8"]), (false, Some("5".into())));
+        // Taking a break isn't a disagreement.
+        assert_eq!(consensus(rule, &["5", "☕"]), (true, Some("5".into())));
+        assert_eq!(consensus(rule, &[]), (false, None));
+    }
+
+    #[test]
+    fn adjacent() {
+        let rule = ConsensusRule::Adjacent;
+        assert_eq!(consensus(rule, &["5", "8", "8"]), (true, Some("8".into())));
+        assert_eq!(consensus(rule, &["3", "8"]), (false, Some("5".into())));
+        assert_eq!(consensus(rule, &["5", "?"]), (false, Some("5".into())));
+    }
+
+    #[test]
+    fn supermajority() {
+        let rule = ConsensusRule::Supermajority { percent: 75 };
+        assert_eq!(
+            consensus(rule, &["8", "8", "8", "13"]),
+            (true, Some("8".into()))
+        );
+        assert_eq!(
+            consensus(rule, &["8", "8", "13"]),
+            (false, Some("8".into()))
+        );
+        assert_eq!(
+            consensus(rule, &["8", "8", "8", "∞"]),
+            (true, Some("8".into()))
+        );
+    }
+
+    #[test]
+    fn no_consensus_on_special_cards() {
+        let unanimous = ConsensusRule::Unanimous;
+        assert_eq!(consensus(unanimous, &["?", "?"]), (false, None));
+        assert_eq!(consensus(unanimous, &["∞", "∞"]), (false, None));
+        let supermajority = ConsensusRule::Supermajority { percent: 60 };
+        assert_eq!(
+            consensus(supermajority, &["?", "?", "?", "3"]),
+            (false, Some("3".into()))
+        );
+    }
+}
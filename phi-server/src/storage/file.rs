@@ -0,0 +1,382 @@
+//! Game state is kept in memory, but written to a file so a redeploy doesn't
+//! wipe every player and vote.
+//!
+//! The audit log goes to a second file next to it, one JSON entry per line,
+//! so it only ever gets appended to instead of being rewritten with every
+//! save.
+
+use crate::poker::GameState;
+use crate::rooms::RoomId;
+use crate::storage::{AuditEntry, MemoryStorage, RoomRecord, Storage, StorageError};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct RoomSnapshot {
+    #[serde(flatten)]
+    room: RoomRecord,
+    game_state: GameState,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct Snapshot {
+    rooms: Vec<RoomSnapshot>,
+    /// Only read, from state files written before the audit log got a file
+    /// of its own.
+    #[serde(default, skip_serializing)]
+    audit: Vec<AuditEntry>,
+}
+
+/// Reads a snapshot from disk. A missing file is not an error, since that's
+/// what the first run looks like.
+fn load(path: &Path) -> Result<Option<Snapshot>, StorageError> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(Some(serde_json::from_slice(&bytes)?))
+}
+
+/// Where the audit log for the state file at `path` goes.
+fn audit_path(path: &Path) -> PathBuf {
+    path.with_extension("audit.jsonl")
+}
+
+/// Reads every entry in the audit log file, oldest first.
+fn load_audit(path: &Path) -> Result<Vec<AuditEntry>, StorageError> {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            entries.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(entries)
+}
+
+/// Appends the entries to the audit log, making sure they're on disk before
+/// returning.
+fn append_audit(path: &Path, entries: &[AuditEntry]) -> Result<(), StorageError> {
+    let mut lines = Vec::new();
+    for entry in entries {
+        serde_json::to_writer(&mut lines, entry)?;
+        lines.push(b'\n');
+    }
+    let created = !path.exists();
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&lines)?;
+    file.sync_data()?;
+    if created {
+        sync_parent(path)?;
+    }
+    Ok(())
+}
+
+/// Writes the snapshot next to the target, syncs it, then renames it into
+/// place so a crash or power loss mid-write can't leave a truncated file
+/// behind.
+fn save(path: &Path, snapshot: &Snapshot) -> Result<(), StorageError> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let bytes = serde_json::to_vec(snapshot)?;
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(&bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    sync_parent(path)?;
+    Ok(())
+}
+
+/// Syncs the directory holding `path`, so a file created or renamed into it
+/// is still there after a power loss.
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened to sync them here, so the rename is as
+/// durable as it gets.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// In-memory storage that is snapshotted to a file after it changes.
+pub struct FileStorage {
+    path: PathBuf,
+    audit_path: PathBuf,
+    memory: MemoryStorage,
+    /// Signalled whenever the game state in any room changes.
+    changed: Notify,
+    /// Held while saving, so the writer task and the flush on shutdown don't
+    /// both write the temporary file at once.
+    saving: Mutex<()>,
+    /// Held while appending to the audit log, so entries don't interleave.
+    auditing: Mutex<()>,
+}
+
+impl FileStorage {
+    pub fn open(path: PathBuf) -> Result<FileStorage, StorageError> {
+        let memory = MemoryStorage::default();
+        let audit_path = audit_path(&path);
+        let mut migrated = false;
+        if let Some(snapshot) = load(&path)? {
+            for RoomSnapshot { room, game_state } in snapshot.rooms {
+                memory.insert(room, game_state);
+            }
+            if !snapshot.audit.is_empty() {
+                append_audit(&audit_path, &snapshot.audit)?;
+                migrated = true;
+            }
+        }
+        memory.restore_audit(load_audit(&audit_path)?);
+        let storage = FileStorage {
+            path,
+            audit_path,
+            memory,
+            changed: Notify::new(),
+            saving: Mutex::new(()),
+            auditing: Mutex::new(()),
+        };
+        // Saving straight away means the old entries can't be moved twice.
+        if migrated {
+            storage.flush()?;
+        }
+        Ok(storage)
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            rooms: self
+                .memory
+                .dump()
+                .into_iter()
+                .map(|(room, game_state)| RoomSnapshot { room, game_state })
+                .collect(),
+            audit: Vec::new(),
+        }
+    }
+
+    /// Saves each time something changes, waiting for `debounce` to pass
+    /// first so a burst of mutations only results in a single write.
+    pub async fn run_writer(self: Arc<Self>, debounce: Duration) {
+        loop {
+            self.changed.notified().await;
+            tokio::time::sleep(debounce).await;
+            if let Err(e) = self.flush() {
+                log::error!("Failed to write state file {:?}: {}", &self.path, e);
+            } else {
+                log::trace!("Wrote state file {:?}", &self.path);
+            }
+        }
+    }
+}
+
+impl Storage for FileStorage {
+    fn load_rooms(&self) -> Result<Vec<RoomRecord>, StorageError> {
+        self.memory.load_rooms()
+    }
+
+    fn create_room(&self, room: &RoomRecord) -> Result<(), StorageError> {
+        self.memory.create_room(room)?;
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    fn delete_room(&self, room: RoomId) -> Result<(), StorageError> {
+        self.memory.delete_room(room)?;
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    fn read_game_state(
+        &self,
+        room: RoomId,
+        f: &mut dyn FnMut(&GameState),
+    ) -> Result<(), StorageError> {
+        self.memory.read_game_state(room, f)
+    }
+
+    fn update_game_state(
+        &self,
+        room: RoomId,
+        f: &mut dyn FnMut(&mut GameState),
+    ) -> Result<(), StorageError> {
+        self.memory.update_game_state(room, f)?;
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    fn append_audit(&self, entry: &AuditEntry) -> Result<(), StorageError> {
+        let _auditing = self.auditing.lock().unwrap();
+        append_audit(&self.audit_path, std::slice::from_ref(entry))?;
+        self.memory.append_audit(entry)
+    }
+
+    fn audit_log(&self, limit: usize) -> Result<Vec<AuditEntry>, StorageError> {
+        self.memory.audit_log(limit)
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        let _saving = self.saving.lock().unwrap();
+        save(&self.path, &self.snapshot())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deck::Decks;
+    use crate::poker::{Player, PlayerId, Story};
+    use crate::rooms::RoomRegistry;
+    use std::time::SystemTime;
+
+    /// A directory of its own under the system temp dir, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> TempDir {
+            let dir = std::env::temp_dir().join(format!("phi-{}", uuid::Uuid::new_v4()));
+            fs::create_dir(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn state_file(&self) -> PathBuf {
+            self.0.join("state.json")
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn entry(action: &str) -> AuditEntry {
+        AuditEntry {
+            at: SystemTime::now(),
+            actor: "admin key".to_string(),
+            player_id: PlayerId::new_v4(),
+            player_name: "Ada".to_string(),
+            room_id: None,
+            action: action.to_string(),
+            target: None,
+            details: None,
+        }
+    }
+
+    fn stored(storage: &dyn Storage, room: RoomId) -> GameState {
+        let mut copy = None;
+        storage
+            .read_game_state(room, &mut |game_state| copy = Some(game_state.clone()))
+            .unwrap();
+        copy.unwrap()
+    }
+
+    fn actions(storage: &FileStorage) -> Vec<String> {
+        let log = storage.audit_log(10).unwrap();
+        log.into_iter().map(|entry| entry.action).collect()
+    }
+
+    #[test]
+    fn the_audit_log_is_appended_to_its_own_file() {
+        let dir = TempDir::new();
+        let storage = FileStorage::open(dir.state_file()).unwrap();
+        storage.append_audit(&entry("reset")).unwrap();
+        storage.append_audit(&entry("call")).unwrap();
+        storage.flush().unwrap();
+
+        let snapshot = load(&dir.state_file()).unwrap().unwrap();
+        assert!(snapshot.audit.is_empty());
+        let storage = FileStorage::open(dir.state_file()).unwrap();
+        assert_eq!(actions(&storage), ["call", "reset"]);
+    }
+
+    #[test]
+    fn audit_entries_move_out_of_old_state_files() {
+        let dir = TempDir::new();
+        let old = serde_json::json!({ "rooms": [], "audit": [entry("reset")] });
+        fs::write(dir.state_file(), old.to_string()).unwrap();
+
+        let storage = FileStorage::open(dir.state_file()).unwrap();
+        assert_eq!(actions(&storage), ["reset"]);
+        let storage = FileStorage::open(dir.state_file()).unwrap();
+        assert_eq!(actions(&storage), ["reset"]);
+        let snapshot = load(&dir.state_file()).unwrap().unwrap();
+        assert!(snapshot.audit.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn snapshots_load_back_with_fresh_heartbeats() {
+        let dir = TempDir::new();
+        let registry = |storage: Arc<FileStorage>| {
+            let decks = Decks::default();
+            let default_deck = decks.get("fib").unwrap();
+            RoomRegistry::new(
+                Arc::new(decks),
+                default_deck,
+                Duration::from_secs(60),
+                storage,
+                10,
+            )
+        };
+
+        let storage = Arc::new(FileStorage::open(dir.state_file()).unwrap());
+        let rooms = registry(storage.clone());
+        let room = rooms
+            .create(Some("team".to_string()), None, None, None)
+            .unwrap();
+        let long_ago = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        let mut player = Player::new("Ada".to_string(), PlayerId::new_v4(), Default::default());
+        player.selected_card = Some(3);
+        player.last_heartbeat = long_ago;
+        room.session
+            .update(|game_state| {
+                game_state.players.insert(player.id, player.clone());
+                game_state
+                    .stories
+                    .push(Story::new("Login page".to_string(), None, None));
+            })
+            .unwrap();
+        let saved = room.session.read(GameState::clone).unwrap();
+        storage.flush().unwrap();
+        rooms.shutdown();
+
+        let storage = Arc::new(FileStorage::open(dir.state_file()).unwrap());
+        assert_eq!(stored(&*storage, room.id), saved);
+
+        let restored_at = SystemTime::now();
+        let rooms = registry(storage);
+        rooms.restore(None).unwrap();
+        // The first sweep of the reaper runs straight away, and mustn't see
+        // the heartbeats from before the restart.
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        let restored = rooms.get("team").expect("the room is restored");
+        assert_eq!(restored.id, room.id);
+        assert_eq!(restored.session.admin_key, room.session.admin_key);
+        let game_state = restored.session.read(GameState::clone).unwrap();
+        let restored_player = &game_state.players[&player.id];
+        assert_eq!(restored_player.selected_card, Some(3));
+        assert!(restored_player.last_heartbeat >= restored_at);
+        assert_eq!(game_state.stories, saved.stories);
+        rooms.shutdown();
+    }
+}
@@ -0,0 +1,25 @@
+CREATE TABLE rooms (
+    id TEXT PRIMARY KEY NOT NULL,
+    slug TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    admin_key TEXT NOT NULL,
+    is_calling INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE TABLE players (
+    room_id TEXT NOT NULL REFERENCES rooms (id) ON DELETE CASCADE,
+    id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    -- Milliseconds since the unix epoch.
+    last_heartbeat INTEGER NOT NULL,
+    PRIMARY KEY (room_id, id)
+);
+
+CREATE TABLE votes (
+    room_id TEXT NOT NULL,
+    player_id TEXT NOT NULL,
+    -- Index into the deck for the room.
+    card INTEGER NOT NULL,
+    PRIMARY KEY (room_id, player_id),
+    FOREIGN KEY (room_id, player_id) REFERENCES players (room_id, id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+-- The name of the deck in play. NULL means the server default.
+ALTER TABLE rooms ADD COLUMN deck TEXT;
@@ -0,0 +1,3 @@
+-- Per-room preferences, stored as JSON since they change shape more often than
+-- the rest of the schema.
+ALTER TABLE rooms ADD COLUMN settings TEXT NOT NULL DEFAULT '{}';
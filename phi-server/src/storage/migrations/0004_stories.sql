@@ -0,0 +1,14 @@
+CREATE TABLE stories (
+    room_id TEXT NOT NULL REFERENCES rooms (id) ON DELETE CASCADE,
+    id TEXT NOT NULL,
+    -- Order in the queue, starting from 0.
+    position INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    description TEXT,
+    link TEXT,
+    -- One of `pending`, `estimating`, `estimated` or `skipped`.
+    status TEXT NOT NULL,
+    PRIMARY KEY (room_id, id)
+);
+
+ALTER TABLE rooms ADD COLUMN current_story TEXT;
@@ -0,0 +1,32 @@
+-- Cards are stored as JSON (label, value and kind) rather than an index into
+-- the deck, so the history still makes sense after a room switches decks.
+
+CREATE TABLE rounds (
+    room_id TEXT NOT NULL REFERENCES rooms (id) ON DELETE CASCADE,
+    id TEXT NOT NULL,
+    -- Order the rounds were played in, starting from 0.
+    position INTEGER NOT NULL,
+    story_id TEXT,
+    story_title TEXT,
+    -- Milliseconds since the unix epoch.
+    started_at INTEGER,
+    called_at INTEGER,
+    ended_at INTEGER NOT NULL,
+    final_estimate TEXT,
+    PRIMARY KEY (room_id, id)
+);
+
+CREATE TABLE round_votes (
+    room_id TEXT NOT NULL,
+    round_id TEXT NOT NULL,
+    player_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    card TEXT,
+    PRIMARY KEY (room_id, round_id, player_id),
+    FOREIGN KEY (room_id, round_id) REFERENCES rounds (room_id, id) ON DELETE CASCADE
+);
+
+ALTER TABLE rooms ADD COLUMN round_started_at INTEGER;
+ALTER TABLE rooms ADD COLUMN called_at INTEGER;
+ALTER TABLE rooms ADD COLUMN accepted_estimate TEXT;
+ALTER TABLE stories ADD COLUMN estimate TEXT;
@@ -0,0 +1,2 @@
+-- The countdown for the round in progress, as JSON.
+ALTER TABLE rooms ADD COLUMN timer TEXT;
@@ -0,0 +1 @@
+ALTER TABLE players ADD COLUMN role TEXT NOT NULL DEFAULT 'voter';
@@ -0,0 +1,10 @@
+CREATE TABLE bans (
+    room_id TEXT NOT NULL REFERENCES rooms (id) ON DELETE CASCADE,
+    id TEXT NOT NULL,
+    player_id TEXT,
+    name TEXT,
+    reason TEXT,
+    -- Milliseconds since the unix epoch.
+    banned_at INTEGER NOT NULL,
+    PRIMARY KEY (room_id, id)
+);
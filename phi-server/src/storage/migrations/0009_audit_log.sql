@@ -0,0 +1,12 @@
+-- Not tied to the rooms table, so the trail outlives the room.
+
+CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    -- Milliseconds since the unix epoch.
+    at INTEGER NOT NULL,
+    actor TEXT NOT NULL,
+    player_id TEXT NOT NULL,
+    player_name TEXT NOT NULL,
+    room_id TEXT,
+    action TEXT NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Null for rounds played before this was kept.
+ALTER TABLE rounds ADD COLUMN anonymous INTEGER;
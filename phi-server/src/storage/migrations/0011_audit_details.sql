@@ -0,0 +1,2 @@
+ALTER TABLE audit_log ADD COLUMN target TEXT;
+ALTER TABLE audit_log ADD COLUMN details TEXT;
@@ -0,0 +1,277 @@
+//! Where the game state for each room lives.
+//!
+//! `PlaySession` goes through a `Storage` for every read and write. The
+//! default keeps everything in memory, same as it's always been, while the
+//! other backends let the game survive a restart.
+
+use crate::poker::{AdminKey, GameState, PlayerId};
+use crate::rooms::RoomId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+pub mod file;
+pub mod sqlite;
+
+/// How many audit log entries are kept in memory. Older ones drop off the
+/// front.
+pub const MEMORY_AUDIT_ENTRIES: usize = 1000;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    Sqlite(rusqlite::Error),
+    UnknownRoom(RoomId),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(e) => write!(f, "Storage IO error: {}", e),
+            StorageError::Json(e) => write!(f, "Storage serialization error: {}", e),
+            StorageError::Sqlite(e) => write!(f, "Storage database error: {}", e),
+            StorageError::UnknownRoom(id) => write!(f, "No stored room with id: `{}`", id),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(e: serde_json::Error) -> Self {
+        StorageError::Json(e)
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        StorageError::Sqlite(e)
+    }
+}
+
+/// Everything about a room that isn't game state.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RoomRecord {
+    pub id: RoomId,
+    pub slug: String,
+    pub name: String,
+    pub admin_key: AdminKey,
+}
+
+/// A privileged action, kept for the audit log.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub at: SystemTime,
+    /// The admin credential used, or how else admin rights were had.
+    pub actor: String,
+    pub player_id: PlayerId,
+    pub player_name: String,
+    /// The room acted on, if any.
+    pub room_id: Option<RoomId>,
+    /// The mutation run.
+    pub action: String,
+    /// The player, story or ban acted on, if any.
+    #[serde(default)]
+    pub target: Option<String>,
+    /// The arguments worth keeping and what came of them. Never holds
+    /// passwords or keys.
+    #[serde(default)]
+    pub details: Option<String>,
+}
+
+pub trait Storage: Send + Sync {
+    /// All the rooms that have been saved, used to fill the registry at
+    /// startup.
+    fn load_rooms(&self) -> Result<Vec<RoomRecord>, StorageError>;
+
+    /// Adds a room with an empty game state.
+    fn create_room(&self, room: &RoomRecord) -> Result<(), StorageError>;
+
+    /// Removes a room and its game state. Unknown rooms are not an error.
+    fn delete_room(&self, room: RoomId) -> Result<(), StorageError>;
+
+    /// Lets `f` look at the game state for a room without copying it.
+    ///
+    /// Updates to the room wait until `f` is done, so keep it short.
+    fn read_game_state(
+        &self,
+        room: RoomId,
+        f: &mut dyn FnMut(&GameState),
+    ) -> Result<(), StorageError>;
+
+    /// Applies `f` to the game state for a room and saves the outcome.
+    ///
+    /// Implementations must not let concurrent updates to the same room
+    /// interleave.
+    fn update_game_state(
+        &self,
+        room: RoomId,
+        f: &mut dyn FnMut(&mut GameState),
+    ) -> Result<(), StorageError>;
+
+    /// Adds an entry to the audit log.
+    fn append_audit(&self, entry: &AuditEntry) -> Result<(), StorageError>;
+
+    /// Up to `limit` entries from the audit log, newest first.
+    fn audit_log(&self, limit: usize) -> Result<Vec<AuditEntry>, StorageError>;
+
+    /// Makes sure nothing is left unsaved. Called on shutdown.
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+/// A room kept by `MemoryStorage`, locked on its own so busy rooms don't
+/// hold up the others.
+struct MemoryRoom {
+    record: RoomRecord,
+    game_state: Mutex<GameState>,
+}
+
+/// Keeps game state in memory only. Everything is lost on restart.
+#[derive(Default)]
+pub struct MemoryStorage {
+    rooms: RwLock<HashMap<RoomId, Arc<MemoryRoom>>>,
+    /// The latest `MEMORY_AUDIT_ENTRIES` entries, oldest first.
+    audit: Mutex<VecDeque<AuditEntry>>,
+}
+
+impl MemoryStorage {
+    /// Every room along with its game state.
+    pub fn dump(&self) -> Vec<(RoomRecord, GameState)> {
+        let rooms: Vec<_> = self.rooms.read().unwrap().values().cloned().collect();
+        rooms
+            .iter()
+            .map(|room| (room.record.clone(), room.game_state.lock().unwrap().clone()))
+            .collect()
+    }
+
+    pub fn insert(&self, room: RoomRecord, game_state: GameState) {
+        let room = MemoryRoom {
+            record: room,
+            game_state: Mutex::new(game_state),
+        };
+        self.rooms
+            .write()
+            .unwrap()
+            .insert(room.record.id, Arc::new(room));
+    }
+
+    /// Replaces the audit log, keeping only the latest entries.
+    pub fn restore_audit(&self, entries: Vec<AuditEntry>) {
+        let skip = entries.len().saturating_sub(MEMORY_AUDIT_ENTRIES);
+        *self.audit.lock().unwrap() = entries.into_iter().skip(skip).collect();
+    }
+
+    /// The room, taken out of the map so only the room itself stays locked
+    /// while it's used.
+    fn room(&self, room: RoomId) -> Result<Arc<MemoryRoom>, StorageError> {
+        self.rooms
+            .read()
+            .unwrap()
+            .get(&room)
+            .cloned()
+            .ok_or(StorageError::UnknownRoom(room))
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load_rooms(&self) -> Result<Vec<RoomRecord>, StorageError> {
+        let rooms = self.rooms.read().unwrap();
+        Ok(rooms.values().map(|room| room.record.clone()).collect())
+    }
+
+    fn create_room(&self, room: &RoomRecord) -> Result<(), StorageError> {
+        self.insert(room.clone(), GameState::default());
+        Ok(())
+    }
+
+    fn delete_room(&self, room: RoomId) -> Result<(), StorageError> {
+        self.rooms.write().unwrap().remove(&room);
+        Ok(())
+    }
+
+    fn read_game_state(
+        &self,
+        room: RoomId,
+        f: &mut dyn FnMut(&GameState),
+    ) -> Result<(), StorageError> {
+        let room = self.room(room)?;
+        let game_state = room.game_state.lock().unwrap();
+        f(&game_state);
+        Ok(())
+    }
+
+    fn update_game_state(
+        &self,
+        room: RoomId,
+        f: &mut dyn FnMut(&mut GameState),
+    ) -> Result<(), StorageError> {
+        let room = self.room(room)?;
+        let mut game_state = room.game_state.lock().unwrap();
+        f(&mut game_state);
+        Ok(())
+    }
+
+    fn append_audit(&self, entry: &AuditEntry) -> Result<(), StorageError> {
+        let mut audit = self.audit.lock().unwrap();
+        if audit.len() == MEMORY_AUDIT_ENTRIES {
+            audit.pop_front();
+        }
+        audit.push_back(entry.clone());
+        Ok(())
+    }
+
+    fn audit_log(&self, limit: usize) -> Result<Vec<AuditEntry>, StorageError> {
+        let audit = self.audit.lock().unwrap();
+        Ok(audit.iter().rev().take(limit).cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(storage: &MemoryStorage, slug: &str) -> RoomId {
+        let record = RoomRecord {
+            id: RoomId::new_v4(),
+            slug: slug.to_string(),
+            name: slug.to_string(),
+            admin_key: "key".to_string(),
+        };
+        storage.create_room(&record).unwrap();
+        record.id
+    }
+
+    #[test]
+    fn rooms_are_locked_one_at_a_time() {
+        let storage = MemoryStorage::default();
+        let busy = room(&storage, "busy");
+        let quiet = room(&storage, "quiet");
+
+        // Would deadlock if one lock covered every room.
+        storage
+            .update_game_state(busy, &mut |busy_state| {
+                storage
+                    .read_game_state(quiet, &mut |quiet_state| {
+                        busy_state.is_calling = !quiet_state.is_calling;
+                    })
+                    .unwrap();
+            })
+            .unwrap();
+        let mut is_calling = false;
+        storage
+            .read_game_state(busy, &mut |game_state| is_calling = game_state.is_calling)
+            .unwrap();
+        assert!(is_calling);
+    }
+}
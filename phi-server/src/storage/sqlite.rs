@@ -0,0 +1,676 @@
+//! Stores rooms, players and votes in an embedded SQLite database.
+
+use crate::deck::Card;
+use crate::poker::{
+    Ban, GameState, Player, PlayerId, Role, Round, RoundId, RoundVote, Story, StoryStatus,
+};
+use crate::rooms::RoomId;
+use crate::storage::{AuditEntry, RoomRecord, Storage, StorageError};
+use rusqlite::types::Type;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use serde::de::DeserializeOwned;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Schema migrations, applied in order. The position in this list is the
+/// schema version, tracked via `PRAGMA user_version`.
+const MIGRATIONS: &[&str] = &[
+    include_str!("migrations/0001_init.sql"),
+    include_str!("migrations/0002_room_deck.sql"),
+    include_str!("migrations/0003_room_settings.sql"),
+    include_str!("migrations/0004_stories.sql"),
+    include_str!("migrations/0005_rounds.sql"),
+    include_str!("migrations/0006_round_timer.sql"),
+    include_str!("migrations/0007_player_roles.sql"),
+    include_str!("migrations/0008_bans.sql"),
+    include_str!("migrations/0009_audit_log.sql"),
+    include_str!("migrations/0010_round_anonymous.sql"),
+    include_str!("migrations/0011_audit_details.sql"),
+];
+
+fn to_millis(t: SystemTime) -> i64 {
+    t.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64
+}
+
+fn from_millis(ms: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(ms.max(0) as u64)
+}
+
+fn parse_id(s: String) -> rusqlite::Result<uuid::Uuid> {
+    s.parse()
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e)))
+}
+
+fn from_json<T: DeserializeOwned>(s: &str) -> rusqlite::Result<T> {
+    serde_json::from_str(s)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e)))
+}
+
+fn card_from_sql(s: Option<String>) -> rusqlite::Result<Option<Card>> {
+    s.as_deref().map(from_json).transpose()
+}
+
+fn card_to_sql(card: &Option<Card>) -> Result<Option<String>, StorageError> {
+    Ok(card.as_ref().map(serde_json::to_string).transpose()?)
+}
+
+fn status_to_sql(status: StoryStatus) -> &'static str {
+    match status {
+        StoryStatus::Pending => "pending",
+        StoryStatus::Estimating => "estimating",
+        StoryStatus::Estimated => "estimated",
+        StoryStatus::Skipped => "skipped",
+    }
+}
+
+fn status_from_sql(s: String) -> rusqlite::Result<StoryStatus> {
+    match s.as_str() {
+        "pending" => Ok(StoryStatus::Pending),
+        "estimating" => Ok(StoryStatus::Estimating),
+        "estimated" => Ok(StoryStatus::Estimated),
+        "skipped" => Ok(StoryStatus::Skipped),
+        _ => Err(rusqlite::Error::InvalidColumnType(
+            0,
+            "status".to_string(),
+            Type::Text,
+        )),
+    }
+}
+
+fn role_to_sql(role: Role) -> &'static str {
+    match role {
+        Role::Voter => "voter",
+        Role::Spectator => "spectator",
+        Role::Facilitator => "facilitator",
+    }
+}
+
+fn role_from_sql(s: String) -> rusqlite::Result<Role> {
+    match s.as_str() {
+        "voter" => Ok(Role::Voter),
+        "spectator" => Ok(Role::Spectator),
+        "facilitator" => Ok(Role::Facilitator),
+        _ => Err(rusqlite::Error::InvalidColumnType(
+            0,
+            "role".to_string(),
+            Type::Text,
+        )),
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), StorageError> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let next = idx + 1;
+        log::info!("Applying database migration {}", next);
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute_batch(&format!("PRAGMA user_version = {}", next))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+    /// Game states read so far, kept in step with every write so reads and
+    /// updates don't have to go back to the database. Only touched while
+    /// holding `conn`, so the two can't disagree.
+    cache: Mutex<HashMap<RoomId, GameState>>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<SqliteStorage, StorageError> {
+        let mut conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL;")?;
+        migrate(&mut conn)?;
+        Ok(SqliteStorage {
+            conn: Mutex::new(conn),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+/// The cached game state for `room`, read from the database the first time.
+fn cached<'a>(
+    cache: &'a mut HashMap<RoomId, GameState>,
+    conn: &mut Connection,
+    room: RoomId,
+) -> Result<&'a mut GameState, StorageError> {
+    match cache.entry(room) {
+        Entry::Occupied(entry) => Ok(entry.into_mut()),
+        Entry::Vacant(entry) => {
+            let tx = conn.transaction()?;
+            Ok(entry.insert(read_game_state(&tx, room)?))
+        }
+    }
+}
+
+fn read_game_state(tx: &Transaction, room: RoomId) -> Result<GameState, StorageError> {
+    let room_id = room.to_string();
+    let mut game_state = tx
+        .query_row(
+            "SELECT is_calling, deck, settings, current_story, round_started_at, called_at,
+                accepted_estimate, timer
+             FROM rooms WHERE id = ?1",
+            params![room_id],
+            |row| {
+                let settings: String = row.get(2)?;
+                let current_story: Option<String> = row.get(3)?;
+                let round_started_at: Option<i64> = row.get(4)?;
+                let called_at: Option<i64> = row.get(5)?;
+                let timer: Option<String> = row.get(7)?;
+                Ok(GameState {
+                    is_calling: row.get(0)?,
+                    deck: row.get(1)?,
+                    settings: from_json(&settings)?,
+                    current_story: current_story.map(parse_id).transpose()?,
+                    round_started_at: round_started_at.map(from_millis),
+                    called_at: called_at.map(from_millis),
+                    accepted_estimate: card_from_sql(row.get(6)?)?,
+                    timer: timer.as_deref().map(from_json).transpose()?,
+                    ..Default::default()
+                })
+            },
+        )
+        .optional()?
+        .ok_or(StorageError::UnknownRoom(room))?;
+
+    let mut stmt = tx.prepare(
+        "SELECT id, title, description, link, status, estimate FROM stories
+         WHERE room_id = ?1
+         ORDER BY position",
+    )?;
+    game_state.stories = stmt
+        .query_map(params![room_id], |row| {
+            Ok(Story {
+                id: parse_id(row.get(0)?)?,
+                title: row.get(1)?,
+                description: row.get(2)?,
+                link: row.get(3)?,
+                status: status_from_sql(row.get(4)?)?,
+                estimate: card_from_sql(row.get(5)?)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut stmt = tx.prepare(
+        "SELECT p.id, p.name, p.last_heartbeat, v.card, p.role
+         FROM players p
+         LEFT JOIN votes v ON v.room_id = p.room_id AND v.player_id = p.id
+         WHERE p.room_id = ?1",
+    )?;
+    game_state.players = stmt
+        .query_map(params![room_id], |row| {
+            let id: PlayerId = parse_id(row.get(0)?)?;
+            let card: Option<i64> = row.get(3)?;
+            Ok(Player {
+                id,
+                name: row.get(1)?,
+                selected_card: card.map(|n| n as usize),
+                last_heartbeat: from_millis(row.get(2)?),
+                role: role_from_sql(row.get(4)?)?,
+            })
+        })?
+        .map(|player| player.map(|player| (player.id, player)))
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut stmt =
+        tx.prepare("SELECT round_id, player_id, name, card FROM round_votes WHERE room_id = ?1")?;
+    let mut votes: HashMap<RoundId, Vec<RoundVote>> = HashMap::new();
+    for vote in stmt.query_map(params![room_id], |row| {
+        Ok((
+            parse_id(row.get(0)?)?,
+            RoundVote {
+                player_id: parse_id(row.get(1)?)?,
+                name: row.get(2)?,
+                card: card_from_sql(row.get(3)?)?,
+            },
+        ))
+    })? {
+        let (round_id, vote) = vote?;
+        votes.entry(round_id).or_default().push(vote);
+    }
+
+    let mut stmt = tx.prepare(
+        "SELECT id, story_id, story_title, started_at, called_at, ended_at, final_estimate,
+            anonymous
+         FROM rounds
+         WHERE room_id = ?1
+         ORDER BY position",
+    )?;
+    game_state.rounds = stmt
+        .query_map(params![room_id], |row| {
+            let id = parse_id(row.get(0)?)?;
+            let story_id: Option<String> = row.get(1)?;
+            let started_at: Option<i64> = row.get(3)?;
+            let called_at: Option<i64> = row.get(4)?;
+            Ok(Round {
+                id,
+                story_id: story_id.map(parse_id).transpose()?,
+                story_title: row.get(2)?,
+                started_at: started_at.map(from_millis),
+                called_at: called_at.map(from_millis),
+                ended_at: from_millis(row.get(5)?),
+                votes: votes.remove(&id).unwrap_or_default(),
+                final_estimate: card_from_sql(row.get(6)?)?,
+                anonymous: row.get(7)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut stmt = tx.prepare(
+        "SELECT id, player_id, name, reason, banned_at FROM bans
+         WHERE room_id = ?1
+         ORDER BY banned_at",
+    )?;
+    game_state.bans = stmt
+        .query_map(params![room_id], |row| {
+            let player_id: Option<String> = row.get(1)?;
+            Ok(Ban {
+                id: parse_id(row.get(0)?)?,
+                player_id: player_id.map(parse_id).transpose()?,
+                name: row.get(2)?,
+                reason: row.get(3)?,
+                banned_at: from_millis(row.get(4)?),
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(game_state)
+}
+
+/// Writes the parts of `game_state` that differ from `prev`.
+fn write_game_state(
+    tx: &Transaction,
+    room: RoomId,
+    prev: &GameState,
+    game_state: &GameState,
+) -> Result<(), StorageError> {
+    let room_id = room.to_string();
+    tx.execute(
+        "UPDATE rooms SET is_calling = ?2, deck = ?3, settings = ?4, current_story = ?5,
+            round_started_at = ?6, called_at = ?7, accepted_estimate = ?8, timer = ?9
+         WHERE id = ?1",
+        params![
+            room_id,
+            game_state.is_calling,
+            game_state.deck,
+            serde_json::to_string(&game_state.settings)?,
+            game_state.current_story.map(|id| id.to_string()),
+            game_state.round_started_at.map(to_millis),
+            game_state.called_at.map(to_millis),
+            card_to_sql(&game_state.accepted_estimate)?,
+            game_state
+                .timer
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()?
+        ],
+    )?;
+
+    // Stories and bans change rarely and there aren't many of them, so any
+    // change replaces them wholesale.
+    if game_state.bans != prev.bans {
+        write_bans(tx, &room_id, &game_state.bans)?;
+    }
+    if game_state.stories != prev.stories {
+        write_stories(tx, &room_id, &game_state.stories)?;
+    }
+
+    // Players change all the time with heartbeats and votes, so only the
+    // ones that changed are written. Their votes go with them.
+    for player_id in prev.players.keys() {
+        if !game_state.players.contains_key(player_id) {
+            tx.execute(
+                "DELETE FROM players WHERE room_id = ?1 AND id = ?2",
+                params![room_id, player_id.to_string()],
+            )?;
+        }
+    }
+    for player in game_state.players.values() {
+        if prev.players.get(&player.id) == Some(player) {
+            continue;
+        }
+        tx.execute(
+            "DELETE FROM players WHERE room_id = ?1 AND id = ?2",
+            params![room_id, player.id.to_string()],
+        )?;
+        write_player(tx, &room_id, player)?;
+    }
+
+    // Rounds never change once played, so only the new ones are written.
+    for (position, round) in game_state.rounds.iter().enumerate().skip(prev.rounds.len()) {
+        write_round(tx, &room_id, position, round)?;
+    }
+    Ok(())
+}
+
+fn write_bans(tx: &Transaction, room_id: &str, bans: &[Ban]) -> Result<(), StorageError> {
+    tx.execute("DELETE FROM bans WHERE room_id = ?1", params![room_id])?;
+    for ban in bans {
+        tx.execute(
+            "INSERT INTO bans (room_id, id, player_id, name, reason, banned_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                room_id,
+                ban.id.to_string(),
+                ban.player_id.map(|id| id.to_string()),
+                ban.name,
+                ban.reason,
+                to_millis(ban.banned_at)
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn write_stories(tx: &Transaction, room_id: &str, stories: &[Story]) -> Result<(), StorageError> {
+    tx.execute("DELETE FROM stories WHERE room_id = ?1", params![room_id])?;
+    for (position, story) in stories.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO stories (room_id, id, position, title, description, link, status,
+                estimate)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                room_id,
+                story.id.to_string(),
+                position as i64,
+                story.title,
+                story.description,
+                story.link,
+                status_to_sql(story.status),
+                card_to_sql(&story.estimate)?
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+fn write_player(tx: &Transaction, room_id: &str, player: &Player) -> Result<(), StorageError> {
+    tx.execute(
+        "INSERT INTO players (room_id, id, name, last_heartbeat, role)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            room_id,
+            player.id.to_string(),
+            player.name,
+            to_millis(player.last_heartbeat),
+            role_to_sql(player.role)
+        ],
+    )?;
+    if let Some(card) = player.selected_card {
+        tx.execute(
+            "INSERT INTO votes (room_id, player_id, card) VALUES (?1, ?2, ?3)",
+            params![room_id, player.id.to_string(), card as i64],
+        )?;
+    }
+    Ok(())
+}
+
+fn write_round(
+    tx: &Transaction,
+    room_id: &str,
+    position: usize,
+    round: &Round,
+) -> Result<(), StorageError> {
+    let inserted = tx.execute(
+        "INSERT OR IGNORE INTO rounds (room_id, id, position, story_id, story_title,
+            started_at, called_at, ended_at, final_estimate, anonymous)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            room_id,
+            round.id.to_string(),
+            position as i64,
+            round.story_id.map(|id| id.to_string()),
+            round.story_title,
+            round.started_at.map(to_millis),
+            round.called_at.map(to_millis),
+            to_millis(round.ended_at),
+            card_to_sql(&round.final_estimate)?,
+            round.anonymous
+        ],
+    )?;
+    if inserted == 0 {
+        return Ok(());
+    }
+    for vote in &round.votes {
+        tx.execute(
+            "INSERT INTO round_votes (room_id, round_id, player_id, name, card)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                room_id,
+                round.id.to_string(),
+                vote.player_id.to_string(),
+                vote.name,
+                card_to_sql(&vote.card)?
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn load_rooms(&self) -> Result<Vec<RoomRecord>, StorageError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT id, slug, name, admin_key FROM rooms")?;
+        let rooms = stmt
+            .query_map([], |row| {
+                Ok(RoomRecord {
+                    id: parse_id(row.get(0)?)?,
+                    slug: row.get(1)?,
+                    name: row.get(2)?,
+                    admin_key: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(rooms)
+    }
+
+    fn create_room(&self, room: &RoomRecord) -> Result<(), StorageError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO rooms (id, slug, name, admin_key) VALUES (?1, ?2, ?3, ?4)",
+            params![room.id.to_string(), room.slug, room.name, room.admin_key],
+        )?;
+        Ok(())
+    }
+
+    fn delete_room(&self, room: RoomId) -> Result<(), StorageError> {
+        let conn = self.conn.lock().unwrap();
+        let mut cache = self.cache.lock().unwrap();
+        // Everything else about the room goes with it, by `ON DELETE CASCADE`.
+        conn.execute("DELETE FROM rooms WHERE id = ?1", [room.to_string()])?;
+        cache.remove(&room);
+        Ok(())
+    }
+
+    fn read_game_state(
+        &self,
+        room: RoomId,
+        f: &mut dyn FnMut(&GameState),
+    ) -> Result<(), StorageError> {
+        let mut conn = self.conn.lock().unwrap();
+        let mut cache = self.cache.lock().unwrap();
+        f(cached(&mut cache, &mut conn, room)?);
+        Ok(())
+    }
+
+    fn update_game_state(
+        &self,
+        room: RoomId,
+        f: &mut dyn FnMut(&mut GameState),
+    ) -> Result<(), StorageError> {
+        let mut conn = self.conn.lock().unwrap();
+        let mut cache = self.cache.lock().unwrap();
+        let prev = cached(&mut cache, &mut conn, room)?;
+        let mut game_state = prev.clone();
+        f(&mut game_state);
+        if game_state != *prev {
+            let tx = conn.transaction()?;
+            write_game_state(&tx, room, prev, &game_state)?;
+            tx.commit()?;
+            // Only once it's safely written, so a failed write leaves the
+            // cache as it was.
+            *prev = game_state;
+        }
+        Ok(())
+    }
+
+    fn append_audit(&self, entry: &AuditEntry) -> Result<(), StorageError> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO audit_log (at, actor, player_id, player_name, room_id, action, target,
+                details)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                to_millis(entry.at),
+                entry.actor,
+                entry.player_id.to_string(),
+                entry.player_name,
+                entry.room_id.map(|id| id.to_string()),
+                entry.action,
+                entry.target,
+                entry.details,
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn audit_log(&self, limit: usize) -> Result<Vec<AuditEntry>, StorageError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT at, actor, player_id, player_name, room_id, action, target, details
+             FROM audit_log
+             ORDER BY id DESC LIMIT ?1",
+        )?;
+        let entries = stmt
+            .query_map(params![limit as i64], |row| {
+                Ok(AuditEntry {
+                    at: from_millis(row.get(0)?),
+                    actor: row.get(1)?,
+                    player_id: parse_id(row.get(2)?)?,
+                    player_name: row.get(3)?,
+                    room_id: row.get::<_, Option<String>>(4)?.map(parse_id).transpose()?,
+                    action: row.get(5)?,
+                    target: row.get(6)?,
+                    details: row.get(7)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deck::Decks;
+    use crate::poker::{BanId, Player};
+
+    fn stored(storage: &SqliteStorage, room: RoomId) -> GameState {
+        let mut copy = None;
+        storage
+            .read_game_state(room, &mut |game_state| copy = Some(game_state.clone()))
+            .unwrap();
+        copy.unwrap()
+    }
+
+    fn open() -> (SqliteStorage, RoomId) {
+        let storage = SqliteStorage::open(Path::new(":memory:")).unwrap();
+        let room = RoomRecord {
+            id: RoomId::new_v4(),
+            slug: "test".to_string(),
+            name: "Test".to_string(),
+            admin_key: "admin key".to_string(),
+        };
+        storage.create_room(&room).unwrap();
+        (storage, room.id)
+    }
+
+    #[test]
+    fn rounds_keep_the_anonymous_flag() {
+        let (storage, room) = open();
+        let deck = Decks::default().get("fib").unwrap();
+        let player = Player::new("Ada".to_string(), PlayerId::new_v4(), Default::default());
+        let play = |game_state: &mut GameState| {
+            game_state.called_at = Some(SystemTime::now());
+            game_state.is_calling = true;
+            game_state.reset_round(&deck);
+        };
+        storage
+            .update_game_state(room, &mut |game_state| {
+                game_state.players.insert(player.id, player.clone());
+                game_state.settings.anonymous = true;
+                play(game_state);
+                game_state.settings.anonymous = false;
+                play(game_state);
+            })
+            .unwrap();
+
+        let rounds = stored(&storage, room).rounds;
+        let anonymous: Vec<_> = rounds.iter().map(|round| round.anonymous).collect();
+        assert_eq!(anonymous, [Some(true), Some(false)]);
+        assert_eq!(rounds[0].votes[0].name, "Ada");
+    }
+
+    #[test]
+    fn writes_go_through_to_the_database() {
+        let path = std::env::temp_dir().join(format!("phi-{}.db", uuid::Uuid::new_v4()));
+        let storage = SqliteStorage::open(&path).unwrap();
+        let room = RoomRecord {
+            id: RoomId::new_v4(),
+            slug: "test".to_string(),
+            name: "Test".to_string(),
+            admin_key: "admin key".to_string(),
+        };
+        storage.create_room(&room).unwrap();
+        // Heartbeats are stored to the millisecond.
+        let player = |name: &str| {
+            let mut player = Player::new(name.to_string(), PlayerId::new_v4(), Role::Voter);
+            player.last_heartbeat = from_millis(to_millis(player.last_heartbeat));
+            player
+        };
+        let (ada, bob, cy) = (player("Ada"), player("Bob"), player("Cy"));
+        let update = |f: &mut dyn FnMut(&mut GameState)| {
+            storage.update_game_state(room.id, f).unwrap();
+        };
+        update(&mut |game_state| {
+            for player in [&ada, &bob, &cy] {
+                game_state.players.insert(player.id, player.clone());
+            }
+            game_state
+                .stories
+                .push(Story::new("Login".to_string(), None, None));
+        });
+        update(&mut |game_state| {
+            game_state.players.get_mut(&ada.id).unwrap().selected_card = Some(3);
+            game_state.players.get_mut(&bob.id).unwrap().name = "Rob".to_string();
+            game_state.players.remove(&cy.id);
+            game_state.bans.push(Ban {
+                id: BanId::new_v4(),
+                player_id: Some(cy.id),
+                name: None,
+                reason: None,
+                banned_at: from_millis(1000),
+            });
+        });
+
+        let cached = stored(&storage, room.id);
+        drop(storage);
+        let reopened = SqliteStorage::open(&path).unwrap();
+        let stored = stored(&reopened, room.id);
+        drop(reopened);
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(stored, cached);
+        assert_eq!(stored.players.len(), 2);
+        assert_eq!(stored.players[&ada.id].selected_card, Some(3));
+        assert_eq!(stored.players[&bob.id].name, "Rob");
+        assert_eq!(stored.stories.len(), 1);
+        assert_eq!(stored.bans.len(), 1);
+    }
+}